@@ -0,0 +1,271 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::usage::Usage;
+
+/// Hard caps; `None` means unlimited. Token caps count input + output tokens.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BudgetLimits {
+    pub daily_tokens: Option<u64>,
+    pub daily_cost: Option<f64>,
+    pub monthly_tokens: Option<u64>,
+    pub monthly_cost: Option<f64>,
+    pub per_game_tokens: Option<u64>,
+    pub per_game_cost: Option<f64>,
+    pub per_ip_daily_tokens: Option<u64>,
+    pub per_ip_daily_cost: Option<f64>,
+}
+
+impl BudgetLimits {
+    pub fn has_per_ip_caps(&self) -> bool {
+        self.per_ip_daily_tokens.is_some() || self.per_ip_daily_cost.is_some()
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading budget limits at {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("parsing budget limits at {}", path.display()))
+    }
+}
+
+fn exceeds(usage: &Usage, tokens: Option<u64>, cost: Option<f64>) -> bool {
+    tokens.is_some_and(|t| usage.total_tokens() >= t)
+        || cost.is_some_and(|c| usage.cost_usd >= c)
+}
+
+/// (year, month, day) for days since the unix epoch.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// UTC ("YYYY-MM-DD", "YYYY-MM") for now.
+fn period_keys() -> (String, String) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (y, m, d) = civil_from_days((secs / 86_400) as i64);
+    (format!("{:04}-{:02}-{:02}", y, m, d), format!("{:04}-{:02}", y, m))
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Counters {
+    day: String,
+    month: String,
+    daily: Usage,
+    monthly: Usage,
+    per_ip: HashMap<IpAddr, Usage>,
+}
+
+impl Counters {
+    fn roll_over(&mut self) {
+        let (day, month) = period_keys();
+        if self.day != day {
+            self.day = day;
+            self.daily = Usage::default();
+            self.per_ip.clear();
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly = Usage::default();
+        }
+    }
+}
+
+/// Spending caps; counters are written to `state_path` by `flush` so they
+/// survive restarts.
+pub struct Budget {
+    limits: BudgetLimits,
+    state_path: Option<PathBuf>,
+    counters: StdMutex<Counters>,
+    /// Charged since the last flush.
+    dirty: AtomicBool,
+}
+
+impl Budget {
+    pub fn load(limits: &BudgetLimits, state_path: Option<&Path>) -> Result<Self> {
+        let counters = match state_path {
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("reading budget state at {}", path.display()))?;
+                serde_json::from_str(&contents)
+                    .with_context(|| format!("parsing budget state at {}", path.display()))?
+            }
+            _ => Counters::default(),
+        };
+        Ok(Self {
+            limits: limits.clone(),
+            state_path: state_path.map(Path::to_path_buf),
+            counters: StdMutex::new(counters),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether another LLM call is allowed for this client and game.
+    pub fn allows(&self, ip: IpAddr, game_usage: &Usage) -> bool {
+        let l = &self.limits;
+        if exceeds(game_usage, l.per_game_tokens, l.per_game_cost) {
+            return false;
+        }
+
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.roll_over();
+        let ip_usage = counters.per_ip.get(&ip).copied().unwrap_or_default();
+        !(exceeds(&counters.daily, l.daily_tokens, l.daily_cost)
+            || exceeds(&counters.monthly, l.monthly_tokens, l.monthly_cost)
+            || exceeds(&ip_usage, l.per_ip_daily_tokens, l.per_ip_daily_cost))
+    }
+
+    pub fn charge(&self, ip: IpAddr, usage: &Usage) {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.roll_over();
+        counters.daily += *usage;
+        counters.monthly += *usage;
+        *counters.per_ip.entry(ip).or_default() += *usage;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Drops counters from past days and writes the state if anything was
+    /// charged since the last call. Blocking; run it off the async workers.
+    pub fn flush(&self) -> Result<()> {
+        let contents = {
+            let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+            counters.roll_over();
+            if self.state_path.is_none() || !self.dirty.swap(false, Ordering::Relaxed) {
+                return Ok(());
+            }
+            serde_json::to_vec(&*counters)?
+        };
+        self.save(&contents).inspect_err(|_| self.dirty.store(true, Ordering::Relaxed))
+    }
+
+    fn save(&self, contents: &[u8]) -> Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("renaming {} to {}", tmp.display(), path.display()))?;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.roll_over();
+        json!({
+            "day": counters.day,
+            "month": counters.month,
+            "daily": counters.daily,
+            "monthly": counters.monthly,
+            "clients_today": counters.per_ip.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    fn usage(tokens: u64, cost_usd: f64) -> Usage {
+        Usage { calls: 1, input_tokens: tokens, cost_usd, ..Usage::default() }
+    }
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn per_game_cap() {
+        let limits = BudgetLimits { per_game_tokens: Some(100), ..BudgetLimits::default() };
+        let budget = Budget::load(&limits, None).unwrap();
+        assert!(budget.allows(IP, &usage(99, 0.0)));
+        assert!(!budget.allows(IP, &usage(100, 0.0)));
+    }
+
+    #[test]
+    fn daily_cap_applies_to_everyone() {
+        let limits = BudgetLimits { daily_tokens: Some(1000), ..BudgetLimits::default() };
+        let budget = Budget::load(&limits, None).unwrap();
+        budget.charge(IP, &usage(600, 0.0));
+        assert!(budget.allows(OTHER_IP, &Usage::default()));
+        budget.charge(IP, &usage(400, 0.0));
+        assert!(!budget.allows(OTHER_IP, &Usage::default()));
+    }
+
+    #[test]
+    fn per_ip_cap_applies_to_one_client() {
+        let limits = BudgetLimits { per_ip_daily_cost: Some(0.5), ..BudgetLimits::default() };
+        let budget = Budget::load(&limits, None).unwrap();
+        budget.charge(IP, &usage(10, 0.5));
+        assert!(!budget.allows(IP, &Usage::default()));
+        assert!(budget.allows(OTHER_IP, &Usage::default()));
+    }
+
+    #[test]
+    fn flush_writes_only_after_a_charge() {
+        let path = TempPath::new("budget-flush.json");
+        let limits = BudgetLimits { daily_tokens: Some(100), ..BudgetLimits::default() };
+
+        let budget = Budget::load(&limits, Some(&path)).unwrap();
+        budget.flush().unwrap();
+        assert!(!path.exists());
+        budget.charge(IP, &usage(100, 0.0));
+        budget.flush().unwrap();
+
+        let reloaded = Budget::load(&limits, Some(&path)).unwrap();
+        assert!(!reloaded.allows(OTHER_IP, &Usage::default()));
+    }
+
+    #[test]
+    fn past_days_are_forgotten() {
+        let path = TempPath::new("budget-rollover.json");
+        let mut counters = Counters {
+            day: "2000-01-01".to_owned(),
+            month: "2000-01".to_owned(),
+            daily: usage(1000, 10.0),
+            monthly: usage(1000, 10.0),
+            ..Counters::default()
+        };
+        counters.per_ip.insert(IP, usage(1000, 10.0));
+        fs::write(&path, serde_json::to_vec(&counters).unwrap()).unwrap();
+
+        let limits = BudgetLimits {
+            daily_tokens: Some(1000),
+            monthly_tokens: Some(1000),
+            per_ip_daily_tokens: Some(1000),
+            ..BudgetLimits::default()
+        };
+        let budget = Budget::load(&limits, Some(&path)).unwrap();
+        assert!(budget.allows(IP, &Usage::default()));
+        assert_eq!(budget.to_json()["clients_today"], 0);
+    }
+}
@@ -0,0 +1,469 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::Deserialize;
+use crate::budget::BudgetLimits;
+use crate::game_store::StoreBackend;
+use crate::gpt::GptClientConfig;
+use crate::key_ring::{KeyLimits, KeySelection};
+use crate::llm::MockClient;
+use crate::model_policy::ModelPolicy;
+use crate::server::client_pool::ClientFactoryConfig;
+use crate::server::listener::{Listen, TlsConfig};
+use crate::server::rate_limit::RateLimits;
+use crate::server::server::Config;
+use crate::subject::SubjectSource;
+use crate::usage::PriceTable;
+
+/// Everything that can be configured. The same fields are read from the
+/// config file, the environment and the command line; later sources win.
+/// Durations are in seconds.
+#[derive(Parser, Deserialize, Default, Debug)]
+#[command(name = "gggame", about = "Twenty questions game server")]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// TOML or JSON (by extension) config file.
+    #[arg(long, short, env = "GGGAME_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on.
+    #[arg(long, env = "GGGAME_BIND")]
+    pub bind: Option<IpAddr>,
+    #[arg(long, short, env = "GGGAME_PORT")]
+    pub port: Option<u16>,
+    /// "host:port", "tls://host:port" or "unix:<path>"; replaces bind and port.
+    #[arg(long, env = "GGGAME_LISTEN", value_delimiter = ',')]
+    pub listen: Option<Vec<String>>,
+    /// PEM certificate chain for tls:// listeners, reloaded when it changes.
+    #[arg(long, env = "GGGAME_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, env = "GGGAME_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Static files served under /static.
+    #[arg(long, env = "GGGAME_WWW_ROOT")]
+    pub www_root: Option<PathBuf>,
+    /// Cap for `?wait=` long-polling.
+    #[arg(long, env = "GGGAME_MAX_WAIT_SECS")]
+    pub max_wait_secs: Option<u64>,
+
+    /// "openai" (any Responses API server) or "mock".
+    #[arg(long, env = "GGGAME_LLM_BACKEND")]
+    pub backend: Option<String>,
+    /// Canned replies for the mock backend.
+    #[arg(long, env = "GGGAME_MOCK_FIXTURE")]
+    pub mock_fixture: Option<PathBuf>,
+    #[arg(long, env = "GGGAME_LLM_BASE_URL")]
+    pub base_url: Option<String>,
+    /// "Name: value; Other: value"
+    #[arg(long, env = "GGGAME_LLM_EXTRA_HEADERS")]
+    pub extra_headers: Option<String>,
+    /// Sent instead of the model picked per task, for servers with their own
+    /// models; turns off the model policy.
+    #[arg(long, env = "GGGAME_LLM_MODEL")]
+    pub model: Option<String>,
+    /// "answer=gpt-5-nano,gpt-5-mini;guess=gpt-5"
+    #[arg(long, env = "GGGAME_MODEL_POLICY")]
+    pub model_policy: Option<String>,
+    /// One API key per line; defaults to `GGGAME_GPT_KEYS` or ~/.gpt.key.
+    #[arg(long, env = "GGGAME_GPT_KEYS_FILE")]
+    pub keys_file: Option<PathBuf>,
+    /// "round_robin" or "least_loaded".
+    #[arg(long, env = "GGGAME_GPT_KEY_SELECTION")]
+    pub key_selection: Option<String>,
+    #[arg(long, env = "GGGAME_GPT_KEY_RPM")]
+    pub key_rpm: Option<u32>,
+    #[arg(long, env = "GGGAME_GPT_KEY_TPM")]
+    pub key_tpm: Option<u64>,
+
+    /// LLM clients kept in the pool.
+    #[arg(long, env = "GGGAME_MAX_CLIENTS")]
+    pub max_clients: Option<i32>,
+    /// Requests allowed to wait for a client.
+    #[arg(long, env = "GGGAME_MAX_QUEUE")]
+    pub max_queue: Option<usize>,
+    #[arg(long, env = "GGGAME_ACQUIRE_TIMEOUT_SECS")]
+    pub acquire_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "GGGAME_ANSWER_CACHE_LIMIT")]
+    pub answer_cache_limit: Option<usize>,
+    /// Where finished answers are kept over a restart.
+    #[arg(long, env = "GGGAME_ANSWER_CACHE_STATE")]
+    pub answer_cache_state: Option<PathBuf>,
+    /// How long a shutdown waits for answers in progress.
+    #[arg(long, env = "GGGAME_SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "GGGAME_MAX_QUESTIONS")]
+    pub max_questions: Option<usize>,
+    #[arg(long, env = "GGGAME_MAX_GAMES")]
+    pub max_games: Option<usize>,
+    #[arg(long, env = "GGGAME_GAME_IDLE_TTL_SECS")]
+    pub game_idle_ttl_secs: Option<u64>,
+    #[arg(long, env = "GGGAME_GAME_FINISHED_TTL_SECS")]
+    pub game_finished_ttl_secs: Option<u64>,
+    /// Minimum time between questions to one game; 0 turns it off.
+    #[arg(long, env = "GGGAME_QUESTION_COOLDOWN_SECS")]
+    pub question_cooldown_secs: Option<u64>,
+    /// "memory" or "file:<path>".
+    #[arg(long, env = "GGGAME_GAME_STORE")]
+    pub game_store: Option<String>,
+    /// "builtin", "llm" or "file:<path>".
+    #[arg(long, env = "GGGAME_SUBJECT_SOURCE")]
+    pub subject_source: Option<String>,
+
+    /// Per client limits, "new_game=10/min;ask=30/min;read=off". Routes are
+    /// token, new_game, ask, guess and read.
+    #[arg(long, env = "GGGAME_RATE_LIMITS")]
+    pub rate_limits: Option<String>,
+    /// Proxies allowed to set `X-Forwarded-For`.
+    #[arg(long, env = "GGGAME_TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Option<Vec<IpAddr>>,
+    /// Take the client address from `X-Real-IP` on unix: listeners, which
+    /// then must be reachable only by the proxy. Required there while rate
+    /// limits or per-IP budget caps are on.
+    #[arg(long, env = "GGGAME_UNIX_TRUST_REAL_IP")]
+    pub unix_trust_real_ip: Option<bool>,
+    /// Bearer token for /api/admin/usage and /api/admin/pool; unset turns them off.
+    #[arg(long, env = "GGGAME_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// JSON model prices.
+    #[arg(long, env = "GGGAME_PRICE_TABLE")]
+    pub price_table: Option<PathBuf>,
+    /// JSON spending caps.
+    #[arg(long, env = "GGGAME_BUDGET_LIMITS")]
+    pub budget_limits: Option<PathBuf>,
+    /// Where spending counters are kept between restarts.
+    #[arg(long, env = "GGGAME_BUDGET_STATE")]
+    pub budget_state: Option<PathBuf>,
+}
+
+/// `self` wins, `lower` fills the gaps.
+macro_rules! merge_options {
+    ($high:expr, $low:expr, $($field:ident),* $(,)?) => {
+        Options { $($field: $high.$field.or($low.$field),)* }
+    };
+}
+
+impl Options {
+    /// Command line and environment on top of the config file.
+    pub fn load() -> Result<Options> {
+        let cli = Options::parse();
+        let file = match &cli.config {
+            Some(path) => Options::from_file(path)?,
+            None => Options::default(),
+        };
+        Ok(cli.or(file))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Options> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        let options = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&contents)
+                .with_context(|| format!("parsing config file {}", path.display()))?,
+            _ => toml::from_str(&contents)
+                .with_context(|| format!("parsing config file {}", path.display()))?,
+        };
+        Ok(options)
+    }
+
+    fn or(self, lower: Options) -> Options {
+        merge_options!(self, lower,
+            config, bind, port, listen, tls_cert, tls_key, www_root, max_wait_secs,
+            backend, mock_fixture, base_url, extra_headers, model, model_policy,
+            keys_file, key_selection, key_rpm, key_tpm,
+            max_clients, max_queue, acquire_timeout_secs,
+            answer_cache_limit, answer_cache_state, shutdown_timeout_secs,
+            max_questions, max_games, game_idle_ttl_secs, game_finished_ttl_secs,
+            question_cooldown_secs, game_store, subject_source,
+            rate_limits, trusted_proxies, unix_trust_real_ip, admin_token,
+            price_table, budget_limits, budget_state,
+        )
+    }
+}
+
+/// Validated settings for the server and the LLM clients.
+pub struct Settings {
+    pub server: Config,
+    pub pool: ClientFactoryConfig,
+    pub gpt: GptClientConfig,
+    pub keys_file: Option<PathBuf>,
+    pub key_selection: KeySelection,
+    pub key_limits: KeyLimits,
+    /// Set when the mock backend is used instead of a real LLM.
+    pub mock: Option<MockClient>,
+}
+
+fn at_least<T: PartialOrd + std::fmt::Display>(name: &str, value: T, min: T) -> Result<T> {
+    if value < min {
+        anyhow::bail!("{} must be at least {}, got {}", name, min, value);
+    }
+    Ok(value)
+}
+
+/// The bundled `www` directory in debug builds, `./www` if present otherwise.
+fn default_www_root() -> Option<PathBuf> {
+    #[cfg(debug_assertions)]
+    {
+        Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("www"))
+    }
+    #[cfg(not(debug_assertions))]
+    {
+        let path = PathBuf::from("www");
+        if path.is_dir() {
+            return Some(path);
+        }
+        tracing::warn!("no www root configured, static files are not served");
+        None
+    }
+}
+
+impl Settings {
+    pub fn resolve(o: Options) -> Result<Settings> {
+        let mut server = Config::default();
+        if let Some(bind) = o.bind {
+            server.bind = bind;
+        }
+        if let Some(port) = o.port {
+            server.port = port;
+        }
+        if let Some(listen) = o.listen {
+            server.listen = listen.iter()
+                .map(|l| l.parse::<Listen>())
+                .collect::<Result<_>>()?;
+        }
+        server.tls = match (o.tls_cert, o.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key, reload_interval: Duration::from_secs(60) }),
+            (None, None) => None,
+            _ => anyhow::bail!("tls_cert and tls_key must be set together"),
+        };
+        if server.tls.is_none() && server.listen.iter().any(|l| matches!(l, Listen::Tls(_))) {
+            anyhow::bail!("tls:// listeners need tls_cert and tls_key");
+        }
+        server.www_root_path = match o.www_root {
+            Some(path) if !path.is_dir() => {
+                anyhow::bail!("www_root {} is not a directory", path.display())
+            }
+            Some(path) => Some(path),
+            None => default_www_root(),
+        };
+        if let Some(secs) = o.max_wait_secs {
+            server.max_wait = Duration::from_secs(secs);
+        }
+        if let Some(limit) = o.answer_cache_limit {
+            server.answer_cache_limit = at_least("answer_cache_limit", limit, 1)?;
+        }
+        if let Some(path) = o.answer_cache_state {
+            server.answer_cache_path = Some(path);
+        }
+        if let Some(secs) = o.shutdown_timeout_secs {
+            server.shutdown_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(n) = o.max_questions {
+            server.games.max_questions = at_least("max_questions", n, 1)?;
+        }
+        if let Some(n) = o.max_games {
+            server.games.max_games = at_least("max_games", n, 1)?;
+        }
+        if let Some(secs) = o.game_idle_ttl_secs {
+            server.games.idle_ttl = Duration::from_secs(at_least("game_idle_ttl_secs", secs, 1)?);
+        }
+        if let Some(secs) = o.game_finished_ttl_secs {
+            server.games.finished_ttl = Duration::from_secs(at_least("game_finished_ttl_secs", secs, 1)?);
+        }
+        if let Some(secs) = o.question_cooldown_secs {
+            server.games.question_cooldown = Duration::from_secs(secs);
+        }
+        if let Some(limits) = o.rate_limits {
+            server.rate_limits = limits.parse::<RateLimits>().context("invalid rate_limits")?;
+        }
+        if let Some(proxies) = o.trusted_proxies {
+            server.trusted_proxies = proxies;
+        }
+        if let Some(trust) = o.unix_trust_real_ip {
+            server.unix_trust_real_ip = trust;
+        }
+        if let Some(token) = o.admin_token {
+            if token.len() < 16 {
+                anyhow::bail!("admin_token must be at least 16 characters");
+            }
+            server.admin_token = Some(token);
+        }
+        if let Some(store) = o.game_store {
+            server.game_store = store.parse::<StoreBackend>()?;
+        }
+        if let Some(source) = o.subject_source {
+            server.subject_source = source.parse::<SubjectSource>()?;
+        }
+        if let Some(policy) = &o.model_policy {
+            server.model_policy = policy.parse::<ModelPolicy>().context("invalid model_policy")?;
+        }
+        if let Some(path) = o.price_table {
+            server.price_table = PriceTable::from_file(&path)?;
+        }
+        if let Some(path) = o.budget_limits {
+            server.budget = BudgetLimits::from_file(&path)?;
+        }
+        if let Some(path) = o.budget_state {
+            server.budget_state_path = Some(path);
+        }
+        // without X-Real-IP every unix: client is 127.0.0.1 and shares one bucket
+        let per_client = !server.rate_limits.is_off() || server.budget.has_per_ip_caps();
+        let unix = server.listen.iter().any(|l| matches!(l, Listen::Unix(_)));
+        if unix && per_client && !server.unix_trust_real_ip {
+            anyhow::bail!("unix: listeners need unix_trust_real_ip while rate limits or per-IP budget caps are on");
+        }
+
+        let mut pool = ClientFactoryConfig {
+            max_clients: 5,
+            max_queue: 20,
+            acquire_timeout: Duration::from_secs(10),
+            max_age: Some(Duration::from_secs(3600)),
+            max_uses: Some(1000),
+            idle_timeout: Some(Duration::from_secs(300)),
+        };
+        if let Some(n) = o.max_clients {
+            pool.max_clients = at_least("max_clients", n, 1)?;
+        }
+        if let Some(n) = o.max_queue {
+            pool.max_queue = n;
+        }
+        if let Some(secs) = o.acquire_timeout_secs {
+            pool.acquire_timeout = Duration::from_secs(secs);
+        }
+
+        let mut gpt = GptClientConfig::default();
+        if let Some(url) = o.base_url {
+            gpt.base_url = url;
+        }
+        if let Some(headers) = o.extra_headers {
+            gpt.extra_headers = GptClientConfig::parse_headers(&headers)?;
+        }
+        if o.model.is_some() {
+            if o.model_policy.is_some() {
+                anyhow::bail!("model and model_policy can't be used together");
+            }
+            server.model_policy = server.model_policy.without_escalation();
+        }
+        gpt.model_override = o.model;
+
+        let key_selection = match o.key_selection {
+            Some(s) => s.parse::<KeySelection>()
+                .map_err(|_| anyhow::anyhow!("key_selection must be round_robin or least_loaded, got '{}'", s))?,
+            None => KeySelection::RoundRobin,
+        };
+        let key_limits = KeyLimits {
+            requests_per_minute: o.key_rpm,
+            tokens_per_minute: o.key_tpm,
+            auth_quarantine: Duration::from_secs(600),
+            rate_limit_quarantine: Duration::from_secs(30),
+        };
+
+        let mock = match o.backend.as_deref() {
+            None | Some("openai") => None,
+            Some("mock") => Some(match &o.mock_fixture {
+                Some(path) => MockClient::from_file(path)?,
+                None => MockClient::new(),
+            }),
+            Some(other) => anyhow::bail!("backend must be openai or mock, got '{}'", other),
+        };
+
+        Ok(Settings {
+            server,
+            pool,
+            gpt,
+            keys_file: o.keys_file,
+            key_selection,
+            key_limits,
+            mock,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    fn error(o: Options) -> String {
+        match Settings::resolve(o) {
+            Ok(_) => panic!("options should have been rejected"),
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    #[test]
+    fn higher_sources_win() {
+        let cli = Options { port: Some(9000), model: Some("local".into()), ..Default::default() };
+        let file = Options { port: Some(8000), max_games: Some(50), ..Default::default() };
+        let o = cli.or(file);
+        assert_eq!(o.port, Some(9000));
+        assert_eq!(o.max_games, Some(50));
+        assert_eq!(o.model.as_deref(), Some("local"));
+        assert_eq!(o.backend, None);
+
+        let settings = Settings::resolve(o).unwrap();
+        assert_eq!(settings.server.port, 9000);
+        assert_eq!(settings.server.games.max_games, 50);
+        assert_eq!(settings.gpt.model_override.as_deref(), Some("local"));
+        assert!(!settings.server.model_policy.escalates());
+    }
+
+    #[test]
+    fn config_files_by_extension() {
+        let toml = TempPath::with_contents("config.toml", "port = 8100\nrate_limits = \"ask=5/min\"\n");
+        let json = TempPath::with_contents("config.json", r#"{"port": 8200, "max_clients": 2}"#);
+        assert_eq!(Options::from_file(&toml).unwrap().port, Some(8100));
+        let o = Options::from_file(&json).unwrap();
+        assert_eq!((o.port, o.max_clients), (Some(8200), Some(2)));
+
+        let typo = TempPath::with_contents("typo.toml", "prot = 8100\n");
+        let e = format!("{:#}", Options::from_file(&typo).unwrap_err());
+        assert!(e.contains("unknown field `prot`"), "{}", e);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let e = error(Options { max_games: Some(0), ..Default::default() });
+        assert_eq!(e, "max_games must be at least 1, got 0");
+        let e = error(Options { tls_cert: Some("cert.pem".into()), ..Default::default() });
+        assert_eq!(e, "tls_cert and tls_key must be set together");
+        let e = error(Options { listen: Some(vec!["tls://127.0.0.1:8443".into()]), ..Default::default() });
+        assert_eq!(e, "tls:// listeners need tls_cert and tls_key");
+        let e = error(Options {
+            model: Some("local".into()),
+            model_policy: Some("answer=gpt-5-nano".into()),
+            ..Default::default()
+        });
+        assert_eq!(e, "model and model_policy can't be used together");
+        let e = error(Options { admin_token: Some("secret".into()), ..Default::default() });
+        assert_eq!(e, "admin_token must be at least 16 characters");
+        let e = error(Options { backend: Some("claude".into()), ..Default::default() });
+        assert_eq!(e, "backend must be openai or mock, got 'claude'");
+        let unix = || Some(vec!["unix:/run/gggame.sock".to_owned()]);
+        let e = error(Options { listen: unix(), ..Default::default() });
+        assert_eq!(e, "unix: listeners need unix_trust_real_ip while rate limits or per-IP budget caps are on");
+        let off = "token=off;new_game=off;ask=off;guess=off;read=off".to_owned();
+        assert!(Settings::resolve(Options { listen: unix(), rate_limits: Some(off), ..Default::default() }).is_ok());
+        assert!(Settings::resolve(Options { listen: unix(), unix_trust_real_ip: Some(true), ..Default::default() }).is_ok());
+        let e = error(Options { rate_limits: Some("ask=lots".into()), ..Default::default() });
+        assert!(e.starts_with("invalid rate_limits"), "{}", e);
+    }
+
+    #[test]
+    fn defaults_need_no_options() {
+        let settings = Settings::resolve(Options::default()).unwrap();
+        assert!(settings.mock.is_none());
+        assert!(settings.server.admin_token.is_none());
+        assert!(settings.server.model_policy.escalates());
+        assert_eq!(settings.pool.max_clients, 5);
+    }
+}
@@ -0,0 +1,224 @@
+#![allow(dead_code)]
+
+use std::sync::Mutex as StdMutex;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use crate::game_manager::{redact_subject, MAX_COMMENT_LENGTH};
+
+/// Subscribers further behind than this miss events.
+const EVENT_BUFFER: usize = 64;
+
+/// Pushed to everybody watching a game.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GameEvent {
+    /// The game changed; the full state is at `/api/game/{token}`.
+    Version { version: u32 },
+    /// A question was accepted and is being answered.
+    Pending { version: u32, question: String },
+    /// The comment of the answer being generated, so far.
+    Comment { text: String },
+    Verdict { version: u32, answer: Value },
+    /// The last event before the server goes away; reconnect later.
+    Restarting,
+}
+
+impl GameEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameEvent::Version { .. } => "version",
+            GameEvent::Pending { .. } => "pending",
+            GameEvent::Comment { .. } => "comment",
+            GameEvent::Verdict { .. } => "verdict",
+            GameEvent::Restarting => "restarting",
+        }
+    }
+}
+
+pub struct EventChannel {
+    tx: broadcast::Sender<GameEvent>,
+}
+
+impl Default for EventChannel {
+    fn default() -> Self {
+        Self { tx: broadcast::channel(EVENT_BUFFER).0 }
+    }
+}
+
+impl EventChannel {
+    pub fn send(&self, event: GameEvent) {
+        // nobody listening is fine
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<GameEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn sender(&self) -> broadcast::Sender<GameEvent> {
+        self.tx.clone()
+    }
+}
+
+struct CommentState {
+    raw: String,
+    sent: String,
+}
+
+/// Turns streamed `verdict_schema` JSON into `Comment` events. The comment
+/// is redacted, and text that might be the start of the subject is held
+/// back until it's clear it isn't.
+pub struct CommentStream {
+    subject: String,
+    tx: broadcast::Sender<GameEvent>,
+    state: StdMutex<CommentState>,
+}
+
+impl CommentStream {
+    pub fn new(subject: &str, tx: broadcast::Sender<GameEvent>) -> Self {
+        Self {
+            subject: subject.to_owned(),
+            tx,
+            state: StdMutex::new(CommentState { raw: String::new(), sent: String::new() }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CommentState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn publish(&self, state: &mut CommentState, text: String) {
+        if text != state.sent {
+            state.sent = text.clone();
+            let _ = self.tx.send(GameEvent::Comment { text });
+        }
+    }
+
+    pub fn push(&self, delta: &str) {
+        let mut state = self.lock();
+        state.raw.push_str(delta);
+        let Some((mut text, done)) = partial_comment(&state.raw) else {
+            return;
+        };
+        if !done {
+            text.truncate(held_back(&text, &self.subject));
+        }
+        let text: String = redact_subject(text.trim(), &self.subject)
+            .chars()
+            .take(MAX_COMMENT_LENGTH)
+            .collect();
+        self.publish(&mut state, text);
+    }
+
+    /// Starts over, e.g. when the question is retried with another model.
+    pub fn reset(&self) {
+        let mut state = self.lock();
+        state.raw.clear();
+        self.publish(&mut state, String::new());
+    }
+}
+
+/// The value of the "comment" field of a possibly incomplete JSON object and
+/// whether the string is complete.
+fn partial_comment(raw: &str) -> Option<(String, bool)> {
+    let key = raw.find("\"comment\"")?;
+    let rest = raw[key + "\"comment\"".len()..]
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start()
+        .strip_prefix('"')?;
+
+    let mut res = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some((res, true)),
+            '\\' => match chars.next() {
+                Some('n') => res.push('\n'),
+                Some('t') => res.push('\t'),
+                Some('r') => res.push('\r'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    if hex.len() < 4 {
+                        return Some((res, false));
+                    }
+                    let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                    res.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                Some(c) => res.push(c),
+                None => return Some((res, false)),
+            },
+            c => res.push(c),
+        }
+    }
+    Some((res, false))
+}
+
+/// Where `text` stops being safe to show: the start of the longest suffix
+/// that is also a prefix of the subject.
+fn held_back(text: &str, subject: &str) -> usize {
+    let lower = text.to_ascii_lowercase();
+    let needle = subject.to_ascii_lowercase();
+    lower
+        .char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| needle.starts_with(&lower[i..]))
+        .unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `deltas` one at a time and collects the comments sent.
+    fn stream(subject: &str, deltas: &[&str]) -> Vec<String> {
+        let (tx, mut rx) = broadcast::channel(EVENT_BUFFER);
+        let comments = CommentStream::new(subject, tx);
+        let mut sent = Vec::new();
+        for delta in deltas {
+            comments.push(delta);
+            while let Ok(event) = rx.try_recv() {
+                let GameEvent::Comment { text } = event else {
+                    panic!("unexpected {} event", event.name());
+                };
+                sent.push(text);
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn escapes_are_decoded() {
+        let raw = r#"{"verdict":"yes","comment":"a \"big\" one\\two\n"}"#;
+        assert_eq!(partial_comment(raw), Some(("a \"big\" one\\two\n".to_owned(), true)));
+        assert_eq!(partial_comment(r#"{"comment": "it says \"#), Some(("it says ".to_owned(), false)));
+        assert_eq!(partial_comment(r#"{"comment": "it says \"hi"#), Some(("it says \"hi".to_owned(), false)));
+        assert_eq!(partial_comment(r#"{"verdict":"yes","comm"#), None);
+        assert_eq!(partial_comment(r#"{"comment":"#), None);
+    }
+
+    #[test]
+    fn unicode_escapes_can_span_deltas() {
+        assert_eq!(partial_comment(r#"{"comment":"caf\u00"#), Some(("caf".to_owned(), false)));
+        let sent = stream("Octopus", &[r#"{"comment":"caf\u00"#, r#"e9 au lait"}"#]);
+        assert_eq!(sent, ["caf", "café au lait"]);
+    }
+
+    #[test]
+    fn possible_subject_is_held_back() {
+        assert_eq!(held_back("It is an Oct", "octopus"), 9);
+        assert_eq!(held_back("It is an Ox", "octopus"), 11);
+        assert_eq!(held_back("It is an o", "octopus"), 9);
+
+        // released once it turns out to be something else
+        let sent = stream("Octopus", &[r#"{"verdict":"no","comment":"Not in Oct"#, r#"ober."}"#]);
+        assert_eq!(sent, ["Not in", "Not in October."]);
+        // redacted once it turns out to be the subject
+        let sent = stream("Octopus", &[r#"{"verdict":"yes","comment":"Yes, an octo"#, r#"pus can"#, r#"."}"#]);
+        assert_eq!(sent, ["Yes, an", "Yes, an *** can", "Yes, an *** can."]);
+        // the end of the comment isn't held back
+        let sent = stream("Octopus", &[r#"{"verdict":"no","comment":"Not an oct"#, r#""}"#]);
+        assert_eq!(sent, ["Not an", "Not an oct"]);
+    }
+}
@@ -0,0 +1,354 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::Mutex as StdMutex;
+use std::thread;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::game_manager::GameState;
+use crate::token::Token;
+
+/// Where games live between restarts.
+pub trait GameStore: Send + Sync {
+    /// Every stored game, called once at startup.
+    fn load(&self) -> Result<Vec<(Token, GameState)>>;
+    /// `game` is a serialized `GameState`.
+    fn save(&self, token: &Token, game: &Value) -> Result<()>;
+    fn remove(&self, token: &Token) -> Result<()>;
+    /// Makes sure everything saved so far survives a crash.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps nothing; games are lost on restart.
+pub struct MemoryStore;
+
+impl GameStore for MemoryStore {
+    fn load(&self) -> Result<Vec<(Token, GameState)>> {
+        Ok(Vec::new())
+    }
+
+    fn save(&self, _token: &Token, _game: &Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove(&self, _token: &Token) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum StoreBackend {
+    #[default]
+    Memory,
+    File(PathBuf),
+}
+
+/// "memory" or "file:<path>".
+impl FromStr for StoreBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "memory" => Ok(StoreBackend::Memory),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(StoreBackend::File(PathBuf::from(path))),
+                _ => anyhow::bail!("game store must be 'memory' or 'file:<path>', got '{}'", s),
+            },
+        }
+    }
+}
+
+impl StoreBackend {
+    pub fn open(&self) -> Result<Box<dyn GameStore>> {
+        Ok(match self {
+            StoreBackend::Memory => Box::new(MemoryStore),
+            StoreBackend::File(path) => Box::new(FileStore::open(path)?),
+        })
+    }
+}
+
+/// A snapshot, or a tombstone for a removed game.
+#[derive(Deserialize)]
+struct Line {
+    token: String,
+    #[serde(default)]
+    game: Option<Value>,
+    #[serde(default)]
+    removed: bool,
+}
+
+struct FileInner {
+    file: File,
+    /// Last line written per game, for compaction.
+    latest: HashMap<Token, String>,
+    appended: usize,
+}
+
+/// Append-only JSON lines, one full snapshot per save. The file is
+/// rewritten with only the latest snapshots at startup and whenever the
+/// stale lines start to dominate.
+pub struct FileStore {
+    path: PathBuf,
+    inner: StdMutex<FileInner>,
+}
+
+/// Compact once there are this many more lines than games.
+const COMPACT_SLACK: usize = 1000;
+
+impl FileStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut latest = HashMap::new();
+        if path.exists() {
+            let file = File::open(path)
+                .with_context(|| format!("opening game store at {}", path.display()))?;
+            for (n, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.with_context(|| format!("reading {}", path.display()))?;
+                // a crash can leave a half written last line
+                let parsed = serde_json::from_str::<Line>(&line)
+                    .ok()
+                    .and_then(|l| Some((Token::from_stringr(&l.token).ok()?, l.removed)));
+                match parsed {
+                    Some((token, true)) => { latest.remove(&token); }
+                    Some((token, false)) => { latest.insert(token, line); }
+                    None => tracing::warn!("skipping bad line {} in {}", n + 1, path.display()),
+                }
+            }
+        }
+
+        let file = Self::rewrite(path, &latest)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            inner: StdMutex::new(FileInner { file, latest, appended: 0 }),
+        })
+    }
+
+    /// Writes the latest snapshots to a fresh file and reopens it for appending.
+    fn rewrite(path: &Path, latest: &HashMap<Token, String>) -> Result<File> {
+        let tmp = path.with_extension("tmp");
+        let mut contents = String::new();
+        for line in latest.values() {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+        fs::write(&tmp, contents).with_context(|| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("renaming {} to {}", tmp.display(), path.display()))?;
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening {}", path.display()))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FileInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn append(&self, inner: &mut FileInner, line: &str) -> Result<()> {
+        writeln!(inner.file, "{}", line)
+            .with_context(|| format!("appending to {}", self.path.display()))?;
+        inner.appended += 1;
+        Ok(())
+    }
+
+    fn maybe_compact(&self, inner: &mut FileInner) -> Result<()> {
+        if inner.appended > inner.latest.len() + COMPACT_SLACK {
+            inner.file = Self::rewrite(&self.path, &inner.latest)?;
+            inner.appended = 0;
+        }
+        Ok(())
+    }
+}
+
+impl GameStore for FileStore {
+    fn load(&self) -> Result<Vec<(Token, GameState)>> {
+        let inner = self.lock();
+        let mut games = Vec::with_capacity(inner.latest.len());
+        for (token, line) in &inner.latest {
+            let line: Line = serde_json::from_str(line)?;
+            let Some(game) = line.game else {
+                continue;
+            };
+            match serde_json::from_value::<GameState>(game) {
+                Ok(game) => games.push((*token, game)),
+                Err(e) => tracing::warn!("skipping unreadable game {}: {}", token, e),
+            }
+        }
+        Ok(games)
+    }
+
+    fn save(&self, token: &Token, game: &Value) -> Result<()> {
+        let line = json!({ "token": token.to_string(), "game": game }).to_string();
+        let mut inner = self.lock();
+        self.append(&mut inner, &line)?;
+        inner.latest.insert(*token, line);
+        self.maybe_compact(&mut inner)
+    }
+
+    fn remove(&self, token: &Token) -> Result<()> {
+        let mut inner = self.lock();
+        if inner.latest.remove(token).is_none() {
+            return Ok(());
+        }
+        let line = json!({ "token": token.to_string(), "removed": true }).to_string();
+        self.append(&mut inner, &line)?;
+        self.maybe_compact(&mut inner)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.lock().file.sync_all()
+            .with_context(|| format!("syncing {}", self.path.display()))
+    }
+}
+
+enum StoreOp {
+    Save(Token, Value),
+    Remove(Token),
+    /// Answered once everything sent before it is on disk.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Runs a store on its own thread, so games are written without holding
+/// their locks or blocking the async workers. Writes are applied in order.
+pub struct StoreWriter {
+    tx: mpsc::Sender<StoreOp>,
+}
+
+impl StoreWriter {
+    pub fn spawn(store: Box<dyn GameStore>) -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("game-store".to_owned())
+            .spawn(move || {
+                for op in rx {
+                    let result = match op {
+                        StoreOp::Save(token, game) => store.save(&token, &game)
+                            .with_context(|| format!("saving game {}", token)),
+                        StoreOp::Remove(token) => store.remove(&token)
+                            .with_context(|| format!("removing game {}", token)),
+                        StoreOp::Flush(done) => {
+                            let result = store.flush().context("flushing the game store");
+                            let _ = done.send(());
+                            result
+                        }
+                    };
+                    if let Err(e) = result {
+                        tracing::error!("{:#}", e);
+                    }
+                }
+            })
+            .context("starting the game store writer")?;
+        Ok(Self { tx })
+    }
+
+    pub fn save(&self, token: &Token, game: &GameState) {
+        match serde_json::to_value(game) {
+            Ok(game) => { let _ = self.tx.send(StoreOp::Save(*token, game)); }
+            Err(e) => tracing::error!("serializing game {} failed: {}", token, e),
+        }
+    }
+
+    pub fn remove(&self, token: &Token) {
+        let _ = self.tx.send(StoreOp::Remove(*token));
+    }
+
+    /// Blocks until everything sent so far is written and synced.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.tx.send(StoreOp::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+    use crate::token::TokenType;
+
+    fn game(subject: &str) -> Value {
+        let mut game = serde_json::to_value(GameState::default()).unwrap();
+        game["subject"] = subject.into();
+        game
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn latest_snapshots_survive_a_reopen() {
+        let path = TempPath::new("store-reopen.jsonl");
+        let (kept, removed) = (Token::new(TokenType::Game), Token::new(TokenType::Game));
+        {
+            let store = FileStore::open(&path).unwrap();
+            store.save(&kept, &game("Octopus")).unwrap();
+            store.save(&removed, &game("Tiger")).unwrap();
+            store.save(&kept, &game("Squid")).unwrap();
+            store.remove(&removed).unwrap();
+        }
+        // a crash in the middle of a write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"token\":\"{}\",\"ga", removed).unwrap();
+
+        let store = FileStore::open(&path).unwrap();
+        let games = store.load().unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].0, kept);
+        assert_eq!(games[0].1.get_subject(), "Squid");
+        // compacted on open
+        assert_eq!(lines(&path), 1);
+    }
+
+    #[test]
+    fn stale_lines_are_compacted() {
+        let path = TempPath::new("store-compact.jsonl");
+        let token = Token::new(TokenType::Game);
+        let store = FileStore::open(&path).unwrap();
+        for _ in 0..COMPACT_SLACK {
+            store.save(&token, &game("Octopus")).unwrap();
+        }
+        assert_eq!(lines(&path), COMPACT_SLACK);
+        store.save(&token, &game("Octopus")).unwrap();
+        store.save(&token, &game("Squid")).unwrap();
+        assert_eq!(lines(&path), 1);
+
+        store.save(&token, &game("Whale")).unwrap();
+        let games = FileStore::open(&path).unwrap().load().unwrap();
+        assert_eq!(games[0].1.get_subject(), "Whale");
+    }
+
+    #[test]
+    fn writer_applies_writes_in_order() {
+        let path = TempPath::new("store-writer.jsonl");
+        let token = Token::new(TokenType::Game);
+        let writer = StoreWriter::spawn(Box::new(FileStore::open(&path).unwrap())).unwrap();
+        let mut state: GameState = serde_json::from_value(game("Octopus")).unwrap();
+        writer.save(&token, &state);
+        writer.remove(&token);
+        state = serde_json::from_value(game("Squid")).unwrap();
+        writer.save(&token, &state);
+        writer.flush();
+
+        let games = FileStore::open(&path).unwrap().load().unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].1.get_subject(), "Squid");
+    }
+
+    #[test]
+    fn backend_names() {
+        assert_eq!("memory".parse::<StoreBackend>().unwrap(), StoreBackend::Memory);
+        assert_eq!("file:/var/lib/games.jsonl".parse::<StoreBackend>().unwrap(),
+            StoreBackend::File(PathBuf::from("/var/lib/games.jsonl")));
+        assert!("file:".parse::<StoreBackend>().is_err());
+        assert!("redis".parse::<StoreBackend>().is_err());
+    }
+}
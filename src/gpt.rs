@@ -12,6 +12,15 @@ use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use crate::llm::{AskFuture, LlmClient, LlmError, OnDelta};
+use crate::usage::Usage;
+use crate::key_ring::KeyBinding;
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 
 string_enum! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -23,6 +32,17 @@ string_enum! {
     }
 }
 
+impl Model {
+    /// Next cheaper model, used when this one is rate limited.
+    pub fn cheaper(&self) -> Option<Model> {
+        match self {
+            Model::Gpt5 => Some(Model::Gpt5Mini),
+            Model::Gpt5Mini => Some(Model::Gpt5Nano),
+            Model::Gpt5Nano => None,
+        }
+    }
+}
+
 pub struct Answer {
     response: Response,
     json: Value,
@@ -50,10 +70,30 @@ pub enum OutputItem {
 }
 
 
+#[derive(Deserialize, Default)]
+struct OutputTokensDetails {
+    #[serde(default)]
+    reasoning_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct ResponseUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+    #[serde(default)]
+    output_tokens_details: OutputTokensDetails,
+}
+
 #[derive(Deserialize)]
 pub struct Response {
     #[serde(default)]
     output: Vec<OutputItem>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    usage: Option<ResponseUsage>,
 
 
     //output_text: Option<String>, // sometimes provided by API
@@ -78,8 +118,29 @@ impl Response {
 impl Answer {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
         Ok(Self {
-            json: serde_json::from_slice(&bytes).context("JSON parse failed")?,
-            response: serde_json::from_slice(&bytes)?,
+            json: serde_json::from_slice(bytes).context("JSON parse failed")?,
+            response: serde_json::from_slice(bytes)?,
+        })
+    }
+
+    pub fn from_value(json: Value) -> Result<Self> {
+        Ok(Self {
+            response: serde_json::from_value(json.clone()).context("unexpected response shape")?,
+            json,
+        })
+    }
+
+    /// Wraps plain text into a minimal Responses API payload.
+    pub fn from_text(text: &str) -> Result<Self> {
+        let json = json!({
+            "output": [{
+                "type": "message",
+                "content": [{ "type": "output_text", "text": text }]
+            }]
+        });
+        Ok(Self {
+            response: serde_json::from_value(json.clone())?,
+            json,
         })
     }
 
@@ -87,6 +148,30 @@ impl Answer {
         self.response.first_output_text_typed().map(|s| s.to_string())
     }
 
+    /// Model that actually produced the answer, as reported by the API.
+    pub fn model(&self) -> Option<&str> {
+        self.response.model.as_deref()
+    }
+
+    /// Token counts of the call; cost is filled in by `UsageTracker`.
+    pub fn usage(&self) -> Usage {
+        match &self.response.usage {
+            Some(u) => Usage {
+                input_tokens: u.input_tokens,
+                output_tokens: u.output_tokens,
+                reasoning_tokens: u.output_tokens_details.reasoning_tokens,
+                ..Default::default()
+            },
+            None => Usage::default(),
+        }
+    }
+
+    /// Deserializes the output text of a `json_schema` formatted answer.
+    pub fn parse_json<T: DeserializeOwned>(&self) -> Result<T> {
+        let text = self.response.first_output_text_typed().context("no output text")?;
+        parse_json_lenient(text)
+    }
+
     pub fn dump(&self) {
         if let Ok(s) = serde_json::to_string_pretty(&self.json) {
             println!("{}", s);
@@ -94,9 +179,96 @@ impl Answer {
     }
 }
 
+/// Parses JSON from model output. Falls back to the outermost `{...}` when
+/// the model wraps the object in prose or code fences.
+pub fn parse_json_lenient<T: DeserializeOwned>(text: &str) -> Result<T> {
+    if let Ok(value) = serde_json::from_str(text.trim()) {
+        return Ok(value);
+    }
+    let (Some(start), Some(end)) = (text.find('{'), text.rfind('}')) else {
+        anyhow::bail!("no JSON object in output");
+    };
+    if end < start {
+        anyhow::bail!("no JSON object in output");
+    }
+    serde_json::from_str(&text[start..=end]).context("JSON parse failed")
+}
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub request_timeout: Duration,
+    pub initial_backoff: Duration,
+    /// Also the longest `Retry-After` we are willing to wait for.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            request_timeout: Duration::from_secs(30),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.initial_backoff.saturating_mul(1 << attempt.min(16));
+        let cap = exp.min(self.max_backoff).as_millis() as u64;
+        Duration::from_millis(rand::rng().random_range(0..=cap))
+    }
+}
+
+/// Endpoint settings; lets the client talk to OpenAI-compatible servers.
+#[derive(Clone, Debug)]
+pub struct GptClientConfig {
+    pub base_url: String,
+    pub extra_headers: Vec<(String, String)>,
+    /// Sent instead of `QuestionParams::model` when set.
+    pub model_override: Option<String>,
+    pub retry: RetryPolicy,
+}
+
+impl Default for GptClientConfig {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_owned(),
+            extra_headers: Vec::new(),
+            model_override: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl GptClientConfig {
+    pub fn is_openai(&self) -> bool {
+        self.base_url.trim_end_matches('/') == DEFAULT_BASE_URL
+    }
+
+    /// Parses "Name: value; Other: value" into header pairs.
+    pub fn parse_headers(s: &str) -> Result<Vec<(String, String)>> {
+        let mut res = Vec::new();
+        for item in s.split(';').map(str::trim).filter(|i| !i.is_empty()) {
+            let (name, value) = item
+                .split_once(':')
+                .with_context(|| format!("header '{}' is not in 'Name: value' form", item))?;
+            res.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+        Ok(res)
+    }
+}
+
 pub struct GptClient {
     client: reqwest::Client,
     key: Option<String>,
+    binding: Option<KeyBinding>,
+    config: GptClientConfig,
 }
 
 string_enum! {
@@ -108,12 +280,19 @@ string_enum! {
     }
 }
 
+#[derive(Clone)]
+pub struct JsonSchema {
+    name: String,
+    schema: Value,
+}
+
 pub struct QuestionParams {
     verbosity: Verbosity,
     model: Model,
     instructions: Option<String>,
     max_output_tokens: Option<i32>,
     temperature: Option<f32>,
+    json_schema: Option<JsonSchema>,
 }
 
 impl QuestionParams {
@@ -124,6 +303,7 @@ impl QuestionParams {
             instructions: None,
             max_output_tokens: None,
             temperature: None,
+            json_schema: None,
         }
     }
 
@@ -132,6 +312,10 @@ impl QuestionParams {
         self.model = model;
     }
 
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
     #[allow(dead_code)]
     pub fn set_instructions<S: AsRef<str>>(&mut self, instructions: S) {
         let s = instructions.as_ref().trim();
@@ -148,6 +332,24 @@ impl QuestionParams {
     pub fn set_temperature(&mut self, temperature: f32) {
         self.temperature = Some(temperature);
     }
+
+    /// Asks for structured output matching `schema` (strict `json_schema` format).
+    pub fn set_json_schema(&mut self, name: &str, schema: Value) {
+        self.json_schema = Some(JsonSchema { name: name.to_owned(), schema });
+    }
+
+    fn text_options(&self) -> Value {
+        let mut text = json!({ "verbosity": self.verbosity.to_string() });
+        if let Some(format) = &self.json_schema {
+            text["format"] = json!({
+                "type": "json_schema",
+                "name": format.name,
+                "schema": format.schema,
+                "strict": true,
+            });
+        }
+        text
+    }
 }
 
 #[derive(Serialize)]
@@ -164,19 +366,36 @@ struct RequestBody<'a> {
 
  impl GptClient {
     pub fn new() -> Self {
+        Self::with_config(GptClientConfig::default())
+    }
+
+    pub fn with_config(config: GptClientConfig) -> Self {
         Self {
             client: reqwest::Client::new(),
             key: None,
+            binding: None,
+            config,
         }
     }
 
     fn get_key(&self) -> anyhow::Result<&str> {
+        if let Some(binding) = &self.binding {
+            return Ok(binding.secret());
+        }
         self.key
-            .as_ref()
-            .map(|s| s.as_str())
+            .as_deref()
             .ok_or_else(|| anyhow::anyhow!("key not set"))
     }
 
+    /// Uses a key from the ring instead of a single key file.
+    pub fn bind_key(&mut self, binding: KeyBinding) {
+        self.binding = Some(binding);
+    }
+
+    fn has_key(&self) -> bool {
+        self.binding.is_some() || self.key.is_some()
+    }
+
     pub fn read_gpt_key_from_file(&mut self, path_opt: Option<String>) -> Result<()> {
         let path: PathBuf = match path_opt {
             Some(p) => PathBuf::from(p),
@@ -201,35 +420,358 @@ struct RequestBody<'a> {
 
 
     pub async fn ask(&self, question: &str, params: &QuestionParams) -> Result<Answer> {
+        self.request(question, params, None).await
+    }
+
+    /// Streams the answer (`stream: true`), feeding output text deltas to `on_delta`.
+    pub async fn ask_streaming(&self, question: &str, params: &QuestionParams, on_delta: OnDelta<'_>) -> Result<Answer> {
+        self.request(question, params, Some(on_delta)).await
+    }
+
+    async fn request(&self, question: &str, params: &QuestionParams, on_delta: Option<OnDelta<'_>>) -> Result<Answer> {
+        let model = match &self.config.model_override {
+            Some(model) => model.clone(),
+            None => params.model.to_string(),
+        };
+
         let body = RequestBody {
-            model: params.model.to_string(),
+            model,
             input: question,
             temperature: params.temperature,
             instructions: params.instructions.as_deref(),
             max_output_tokens: params.max_output_tokens,
-            text: json!({ "verbosity": params.verbosity.to_string() }),
+            text: params.text_options(),
         };
 
-        let body = serde_json::to_value(&body)?;
+        let mut body = serde_json::to_value(&body)?;
+        if on_delta.is_some() {
+            body["stream"] = json!(true);
+        }
+        let policy = &self.config.retry;
+
+        // a retry after text went out would show it twice
+        let delivered = AtomicBool::new(false);
+        let tracked = |delta: &str| {
+            delivered.store(true, Ordering::Relaxed);
+            if let Some(on_delta) = on_delta {
+                on_delta(delta);
+            }
+        };
+
+        let mut attempt = 0;
+        loop {
+            let err = match self.send(&body, on_delta.map(|_| &tracked as OnDelta)).await {
+                Ok(answer) => return Ok(answer),
+                Err(e) => e,
+            };
+            attempt += 1;
+            if !err.is_retryable() || attempt >= policy.max_attempts || delivered.load(Ordering::Relaxed) {
+                return Err(err.into());
+            }
+            let delay = err.retry_after().unwrap_or_else(|| policy.backoff(attempt));
+            if delay > policy.max_backoff {
+                return Err(err.into());
+            }
+            tracing::warn!("LLM request failed ({}), retry {} in {:?}", err, attempt, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn send(&self, body: &Value, on_delta: Option<OnDelta<'_>>) -> Result<Answer, LlmError> {
+        let model = body["model"].as_str().unwrap_or_default();
+        if let Some(binding) = &self.binding {
+            binding.check(model)?;
+        }
+        let result = self.send_request(body, on_delta).await;
+        if let Some(binding) = &self.binding {
+            match &result {
+                Ok(answer) => binding.record_tokens(answer.usage().total_tokens()),
+                Err(err) => binding.report(err, model),
+            }
+        }
+        result
+    }
+
+    async fn send_request(&self, body: &Value, on_delta: Option<OnDelta<'_>>) -> Result<Answer, LlmError> {
+        let url = format!("{}/responses", self.config.base_url.trim_end_matches('/'));
+        let mut req = self.client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json");
+
+        // self-hosted servers often run without auth
+        if self.has_key() || self.config.is_openai() {
+            let key = self.get_key().map_err(|_| LlmError::AuthFailed(0))?;
+            req = req.header(AUTHORIZATION, format!("Bearer {}", key));
+        }
+        for (name, value) in &self.config.extra_headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
 
-        let resp = self.client
-            .post("https://api.openai.com/v1/responses")
-            .header(CONTENT_TYPE, "application/json")
-            .header(AUTHORIZATION, format!("Bearer {}", self.get_key()?))
-            .json(&body)
+        let resp = req
+            .timeout(self.config.retry.request_timeout)
+            .json(body)
             .send()
             .await
-            .context("HTTP request failed")?;
+            .map_err(transport_error)?;
 
         let status = resp.status();
-        let bytes = resp.bytes().await.context("reading body failed")?;
+        let retry_after = resp.headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, SystemTime::now()));
+
+        if let (true, Some(on_delta)) = (status.is_success(), on_delta) {
+            return read_stream(resp, on_delta).await;
+        }
+        let bytes = resp.bytes().await.map_err(transport_error)?;
 
         if !status.is_success() {
-            let text = String::from_utf8_lossy(&bytes);
-            anyhow::bail!("OpenAI error {}: {}", status, text);
+            let body = String::from_utf8_lossy(&bytes).to_string();
+            return Err(status_error(status, body, retry_after));
         }
 
-        Ok(Answer::from_bytes(&bytes)?)
+        Answer::from_bytes(&bytes).map_err(|e| LlmError::InvalidResponse(format!("{:#}", e)))
+    }
+}
+
+fn status_error(status: StatusCode, body: String, retry_after: Option<Duration>) -> LlmError {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => LlmError::RateLimited { retry_after },
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => LlmError::AuthFailed(status.as_u16()),
+        s if s.is_server_error() => LlmError::ServerError {
+            status: s.as_u16(),
+            body,
+            retry_after: if s == StatusCode::SERVICE_UNAVAILABLE { retry_after } else { None },
+        },
+        s => LlmError::BadRequest { status: s.as_u16(), body },
+    }
+}
+
+/// `Retry-After` in seconds or as an HTTP date; a date in the past means now.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
     }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Reads the Responses API event stream until the final response arrives.
+async fn read_stream(mut resp: reqwest::Response, on_delta: OnDelta<'_>) -> Result<Answer, LlmError> {
+    let mut events = EventStream::default();
+    while let Some(chunk) = resp.chunk().await.map_err(transport_error)? {
+        if let Some(result) = events.feed(&chunk, on_delta) {
+            return result;
+        }
+    }
+    Err(LlmError::Transport("stream ended before the response completed".to_owned()))
+}
+
+/// Server-sent events split into lines however the chunks fall.
+#[derive(Default)]
+struct EventStream {
+    buf: Vec<u8>,
 }
 
+impl EventStream {
+    /// The outcome once the final event has arrived.
+    fn feed(&mut self, chunk: &[u8], on_delta: OnDelta<'_>) -> Option<Result<Answer, LlmError>> {
+        self.buf.extend_from_slice(chunk);
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+                continue;
+            };
+            match event["type"].as_str().unwrap_or_default() {
+                "response.output_text.delta" => {
+                    if let Some(delta) = event["delta"].as_str() {
+                        on_delta(delta);
+                    }
+                }
+                "response.completed" | "response.incomplete" => {
+                    return Some(Answer::from_value(event["response"].clone())
+                        .map_err(|e| LlmError::InvalidResponse(format!("{:#}", e))));
+                }
+                "response.failed" | "error" => {
+                    return Some(Err(LlmError::ServerError {
+                        status: 0,
+                        body: event.to_string(),
+                        retry_after: None,
+                    }));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+fn transport_error(e: reqwest::Error) -> LlmError {
+    if e.is_timeout() {
+        LlmError::Timeout
+    } else {
+        LlmError::Transport(e.to_string())
+    }
+}
+
+impl LlmClient for GptClient {
+    fn ask<'a>(&'a self, question: &'a str, params: &'a QuestionParams) -> AskFuture<'a> {
+        Box::pin(GptClient::ask(self, question, params))
+    }
+
+    fn ask_streaming<'a>(
+        &'a self,
+        question: &'a str,
+        params: &'a QuestionParams,
+        on_delta: OnDelta<'a>,
+    ) -> AskFuture<'a> {
+        Box::pin(GptClient::ask_streaming(self, question, params, on_delta))
+    }
+
+    fn is_healthy(&self) -> bool {
+        if let Some(binding) = &self.binding {
+            return !binding.is_quarantined();
+        }
+        self.key.is_some() || !self.config.is_openai()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    fn completed(text: &str) -> String {
+        let event = json!({
+            "type": "response.completed",
+            "response": Answer::from_text(text).unwrap().json,
+        });
+        format!("event: response.completed\ndata: {}\n\n", event)
+    }
+
+    fn delta(text: &str) -> String {
+        let event = json!({ "type": "response.output_text.delta", "delta": text });
+        format!("event: response.output_text.delta\ndata: {}\n\n", event)
+    }
+
+    /// Feeds `stream` in chunks of `size` bytes; returns the deltas seen
+    /// and the outcome, if the stream had one.
+    fn read(stream: &str, size: usize) -> (Vec<String>, Option<Result<Answer, LlmError>>) {
+        let deltas = StdMutex::new(Vec::new());
+        let on_delta = |d: &str| deltas.lock().unwrap().push(d.to_owned());
+        let mut events = EventStream::default();
+        let result = stream.as_bytes().chunks(size).find_map(|chunk| events.feed(chunk, &on_delta));
+        (deltas.into_inner().unwrap(), result)
+    }
+
+    #[test]
+    fn stream_ends_with_the_completed_response() {
+        let stream = [delta("It "), delta("is \"big\""), completed("It is \"big\"")].concat();
+        for size in [1, 7, stream.len()] {
+            let (deltas, result) = read(&stream, size);
+            assert_eq!(deltas, ["It ", "is \"big\""]);
+            assert_eq!(result.unwrap().unwrap().to_string().as_deref(), Some("It is \"big\""));
+        }
+    }
+
+    #[test]
+    fn failed_and_truncated_streams() {
+        let failed = json!({ "type": "response.failed", "response": { "error": { "code": "server_error" } } });
+        let (_, result) = read(&format!("{}data: {}\n\n", delta("It"), failed), 5);
+        assert!(matches!(result, Some(Err(LlmError::ServerError { status: 0, .. }))));
+
+        let (deltas, result) = read(&format!("{}data: [DONE]\n\n", delta("It")), 5);
+        assert_eq!(deltas, ["It"]);
+        assert!(result.is_none());
+        // the last line never got its newline
+        let stream = completed("It");
+        let (_, result) = read(stream.trim_end(), 5);
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn truncated_stream_is_a_transport_error() {
+        let resp = reqwest::Response::from(axum::http::Response::new(delta("It")));
+        let result = read_stream(resp, &|_| {}).await;
+        assert!(matches!(result, Err(LlmError::Transport(_))));
+    }
+
+    /// A server that answers one request with `status` and `headers`, or
+    /// never answers when `status` is None.
+    async fn serve_once(status: Option<u16>, headers: &'static str) -> GptClient {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // the body is small JSON ending in '}'
+            while !request.ends_with(b"}") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let Some(status) = status else {
+                return std::future::pending().await;
+            };
+            let resp = format!("HTTP/1.1 {} X\r\n{}content-length: 4\r\nconnection: close\r\n\r\noops", status, headers);
+            socket.write_all(resp.as_bytes()).await.unwrap();
+        });
+        let retry = RetryPolicy { request_timeout: Duration::from_millis(200), ..RetryPolicy::default() };
+        GptClient::with_config(GptClientConfig { base_url, retry, ..GptClientConfig::default() })
+    }
+
+    async fn error_for(status: Option<u16>, headers: &'static str) -> LlmError {
+        let client = serve_once(status, headers).await;
+        match client.send_request(&json!({ "model": "gpt-5" }), None).await {
+            Ok(_) => panic!("{:?} should fail", status),
+            Err(e) => e,
+        }
+    }
+
+    #[tokio::test]
+    async fn http_errors_map_to_llm_errors() {
+        let retry_after = "retry-after: 3\r\n";
+        assert!(matches!(error_for(Some(401), "").await, LlmError::AuthFailed(401)));
+        assert!(matches!(error_for(Some(403), "").await, LlmError::AuthFailed(403)));
+        assert!(matches!(error_for(Some(400), "").await, LlmError::BadRequest { status: 400, .. }));
+        assert!(matches!(error_for(Some(429), retry_after).await,
+            LlmError::RateLimited { retry_after: Some(d) } if d == Duration::from_secs(3)));
+        assert!(matches!(error_for(Some(429), "").await, LlmError::RateLimited { retry_after: None }));
+        assert!(matches!(error_for(Some(500), retry_after).await,
+            LlmError::ServerError { status: 500, retry_after: None, ref body } if body == "oops"));
+        assert!(matches!(error_for(Some(503), retry_after).await,
+            LlmError::ServerError { status: 503, retry_after: Some(_), .. }));
+        assert!(matches!(error_for(None, "").await, LlmError::Timeout));
+    }
+
+    #[test]
+    fn retry_after_is_seconds_or_a_date() {
+        let now = httpdate::parse_http_date("Sun, 18 Oct 2026 10:00:00 GMT").unwrap();
+        assert_eq!(parse_retry_after(" 7 ", now), Some(Duration::from_secs(7)));
+        assert_eq!(parse_retry_after("Sun, 18 Oct 2026 10:00:30 GMT", now), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Sun, 18 Oct 2026 09:00:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            assert!(policy.backoff(0) <= Duration::from_millis(100));
+            assert!(policy.backoff(2) <= Duration::from_millis(400));
+            assert!(policy.backoff(4) <= Duration::from_millis(1000));
+            assert!(policy.backoff(u32::MAX) <= Duration::from_millis(1000));
+        }
+    }
+}
@@ -0,0 +1,343 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use crate::string_enum;
+use crate::llm::LlmError;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+string_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum KeySelection {
+        RoundRobin => "round_robin",
+        LeastLoaded => "least_loaded",
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyLimits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u64>,
+    /// How long a key that got 401 is kept out of rotation.
+    pub auth_quarantine: Duration,
+    /// Used for 429 answers without `Retry-After`.
+    pub rate_limit_quarantine: Duration,
+}
+
+/// Never prints the secret; logs only ever see "key #N".
+struct ApiKey {
+    secret: String,
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiKey(***)")
+    }
+}
+
+#[derive(Default, Debug)]
+struct KeyState {
+    bound_clients: usize,
+    window_start: Option<Instant>,
+    requests: u32,
+    tokens: u64,
+    quarantined_until: Option<Instant>,
+    /// 429s are per model, so other models on the key stay usable.
+    model_quarantine: HashMap<String, Instant>,
+}
+
+impl KeyState {
+    fn roll_window(&mut self, now: Instant) {
+        if self.window_start.is_none_or(|s| now.duration_since(s) >= WINDOW) {
+            self.window_start = Some(now);
+            self.requests = 0;
+            self.tokens = 0;
+        }
+    }
+
+    fn is_quarantined(&self, now: Instant) -> bool {
+        self.quarantined_until.is_some_and(|until| until > now)
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyRing {
+    keys: Vec<ApiKey>,
+    states: StdMutex<Vec<KeyState>>,
+    next: AtomicUsize,
+    selection: KeySelection,
+    limits: KeyLimits,
+}
+
+impl KeyRing {
+    pub fn new(secrets: Vec<String>, selection: KeySelection, limits: KeyLimits) -> Self {
+        let states = secrets.iter().map(|_| KeyState::default()).collect();
+        Self {
+            keys: secrets.into_iter().map(|secret| ApiKey { secret }).collect(),
+            states: StdMutex::new(states),
+            next: AtomicUsize::new(0),
+            selection,
+            limits,
+        }
+    }
+
+    /// One key per line; empty lines and `#` comments are skipped.
+    pub fn read_keys_file(path: &Path) -> Result<Vec<String>> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading key file at {}", path.display()))?;
+        let keys: Vec<String> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(str::to_owned)
+            .collect();
+        if keys.is_empty() {
+            anyhow::bail!("key file {} has no keys", path.display());
+        }
+        Ok(keys)
+    }
+
+    fn parse_keys(keys: &str) -> Result<Vec<String>> {
+        let keys: Vec<String> = keys
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if keys.is_empty() {
+            anyhow::bail!("GGGAME_GPT_KEYS has no keys");
+        }
+        Ok(keys)
+    }
+
+    /// `keys_file`, then comma separated `GGGAME_GPT_KEYS`, then `~/.gpt.key`.
+    pub fn load_keys(keys_file: Option<&Path>) -> Result<Vec<String>> {
+        if let Some(path) = keys_file {
+            return Self::read_keys_file(path);
+        }
+        if let Ok(keys) = env::var("GGGAME_GPT_KEYS") {
+            return Self::parse_keys(&keys);
+        }
+        let home = env::var("HOME").context("HOME is not set; configure a keys file or set GGGAME_GPT_KEYS")?;
+        Self::read_keys_file(&PathBuf::from(home).join(".gpt.key"))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<KeyState>> {
+        self.states.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Binds a new client to a key. Quarantined keys are only used when
+    /// there is nothing else.
+    pub fn bind(self: &Arc<Self>) -> Option<KeyBinding> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        let mut states = self.lock();
+        let n = self.keys.len();
+        let index = match self.selection {
+            KeySelection::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..n)
+                    .map(|i| (start + i) % n)
+                    .find(|&i| !states[i].is_quarantined(now))
+                    .unwrap_or(start % n)
+            }
+            KeySelection::LeastLoaded => (0..n)
+                .min_by_key(|&i| (states[i].is_quarantined(now), states[i].bound_clients))
+                .unwrap_or(0),
+        };
+        states[index].bound_clients += 1;
+        Some(KeyBinding { ring: Arc::clone(self), index })
+    }
+
+    fn check(&self, index: usize, model: &str) -> Result<(), LlmError> {
+        let now = Instant::now();
+        let mut states = self.lock();
+        let state = &mut states[index];
+        if let Some(until) = state.quarantined_until.filter(|&u| u > now) {
+            return Err(LlmError::RateLimited { retry_after: Some(until - now) });
+        }
+        state.model_quarantine.retain(|_, until| *until > now);
+        if let Some(until) = state.model_quarantine.get(model) {
+            return Err(LlmError::RateLimited { retry_after: Some(*until - now) });
+        }
+        state.roll_window(now);
+        let retry_after = state.window_start.map(|s| WINDOW.saturating_sub(now - s));
+        if self.limits.requests_per_minute.is_some_and(|l| state.requests >= l)
+            || self.limits.tokens_per_minute.is_some_and(|l| state.tokens >= l) {
+            return Err(LlmError::RateLimited { retry_after });
+        }
+        state.requests += 1;
+        Ok(())
+    }
+
+    fn record_tokens(&self, index: usize, tokens: u64) {
+        let mut states = self.lock();
+        states[index].roll_window(Instant::now());
+        states[index].tokens += tokens;
+    }
+
+    fn quarantine(&self, index: usize, duration: Duration) {
+        tracing::warn!("API key #{} quarantined for {:?}", index + 1, duration);
+        self.lock()[index].quarantined_until = Some(Instant::now() + duration);
+    }
+
+    fn quarantine_model(&self, index: usize, model: &str, duration: Duration) {
+        tracing::warn!("API key #{} quarantined for {} for {:?}", index + 1, model, duration);
+        self.lock()[index].model_quarantine.insert(model.to_owned(), Instant::now() + duration);
+    }
+}
+
+/// A client's claim on one key of the ring; released when the client is dropped.
+#[derive(Debug)]
+pub struct KeyBinding {
+    ring: Arc<KeyRing>,
+    index: usize,
+}
+
+impl KeyBinding {
+    pub fn secret(&self) -> &str {
+        &self.ring.keys[self.index].secret
+    }
+
+    /// Fails fast when the key is quarantined or over its per-minute limits.
+    pub fn check(&self, model: &str) -> Result<(), LlmError> {
+        self.ring.check(self.index, model)
+    }
+
+    pub fn record_tokens(&self, tokens: u64) {
+        self.ring.record_tokens(self.index, tokens);
+    }
+
+    /// Takes the key (or the key for this model) out of rotation after the
+    /// API rejected it.
+    pub fn report(&self, err: &LlmError, model: &str) {
+        let limits = &self.ring.limits;
+        match err {
+            LlmError::AuthFailed(_) => self.ring.quarantine(self.index, limits.auth_quarantine),
+            LlmError::RateLimited { retry_after } => self.ring.quarantine_model(
+                self.index, model, retry_after.unwrap_or(limits.rate_limit_quarantine)),
+            _ => {}
+        }
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.ring.lock()[self.index].is_quarantined(Instant::now())
+    }
+}
+
+impl Drop for KeyBinding {
+    fn drop(&mut self) {
+        let mut states = self.ring.lock();
+        states[self.index].bound_clients -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    fn ring(n: usize, selection: KeySelection, limits: KeyLimits) -> Arc<KeyRing> {
+        let secrets = (1..=n).map(|i| format!("sk-{}", i)).collect();
+        Arc::new(KeyRing::new(secrets, selection, limits))
+    }
+
+    fn limits() -> KeyLimits {
+        KeyLimits {
+            auth_quarantine: Duration::from_secs(600),
+            rate_limit_quarantine: Duration::from_secs(30),
+            ..KeyLimits::default()
+        }
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let ring = ring(3, KeySelection::RoundRobin, limits());
+        let secrets: Vec<String> = (0..4).map(|_| ring.bind().unwrap().secret().to_owned()).collect();
+        assert_eq!(secrets, ["sk-1", "sk-2", "sk-3", "sk-1"]);
+    }
+
+    #[test]
+    fn least_loaded_spreads_clients() {
+        let ring = ring(2, KeySelection::LeastLoaded, limits());
+        let first = ring.bind().unwrap();
+        let second = ring.bind().unwrap();
+        assert_ne!(first.secret(), second.secret());
+        drop(first);
+        assert_eq!(ring.bind().unwrap().secret(), "sk-1");
+    }
+
+    #[test]
+    fn quarantined_keys_are_skipped() {
+        let ring = ring(2, KeySelection::RoundRobin, limits());
+        let bad = ring.bind().unwrap();
+        bad.report(&LlmError::AuthFailed(401), "gpt-5");
+        assert!(bad.is_quarantined());
+        assert!(matches!(bad.check("gpt-5"), Err(LlmError::RateLimited { .. })));
+
+        for _ in 0..3 {
+            assert_eq!(ring.bind().unwrap().secret(), "sk-2");
+        }
+    }
+
+    #[test]
+    fn rate_limits_quarantine_one_model() {
+        let ring = ring(1, KeySelection::RoundRobin, limits());
+        let key = ring.bind().unwrap();
+        key.report(&LlmError::RateLimited { retry_after: Some(Duration::from_secs(5)) }, "gpt-5");
+        assert!(!key.is_quarantined());
+        assert!(key.check("gpt-5").is_err());
+        assert!(key.check("gpt-5-mini").is_ok());
+    }
+
+    #[test]
+    fn per_minute_limits() {
+        let key = ring(1, KeySelection::RoundRobin, KeyLimits { requests_per_minute: Some(2), ..limits() })
+            .bind()
+            .unwrap();
+        assert!(key.check("gpt-5").is_ok());
+        assert!(key.check("gpt-5").is_ok());
+        assert!(matches!(key.check("gpt-5"), Err(LlmError::RateLimited { retry_after: Some(_) })));
+
+        let key = ring(1, KeySelection::RoundRobin, KeyLimits { tokens_per_minute: Some(1000), ..limits() })
+            .bind()
+            .unwrap();
+        assert!(key.check("gpt-5").is_ok());
+        key.record_tokens(1000);
+        assert!(key.check("gpt-5").is_err());
+    }
+
+    #[test]
+    fn keys_file_skips_comments() {
+        let path = TempPath::with_contents("keys", "# production\nsk-1\n\n  sk-2  \n");
+        assert_eq!(KeyRing::read_keys_file(&path).unwrap(), ["sk-1", "sk-2"]);
+        fs::write(&path, "# nothing here\n").unwrap();
+        assert!(KeyRing::read_keys_file(&path).is_err());
+    }
+
+    #[test]
+    fn keys_env_must_have_a_key() {
+        assert_eq!(KeyRing::parse_keys(" sk-1, ,sk-2").unwrap(), ["sk-1", "sk-2"]);
+        assert!(KeyRing::parse_keys("").is_err());
+        assert!(KeyRing::parse_keys(" , ").is_err());
+    }
+}
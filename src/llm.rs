@@ -0,0 +1,170 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use crate::gpt::{Answer, QuestionParams};
+
+pub type AskFuture<'a> = Pin<Box<dyn Future<Output = Result<Answer>> + Send + 'a>>;
+
+/// Receives output text deltas while a streamed answer is being generated.
+pub type OnDelta<'a> = &'a (dyn Fn(&str) + Send + Sync);
+
+/// Anything that can answer a question the way the Responses API does.
+pub trait LlmClient: Send + Sync {
+    fn ask<'a>(&'a self, question: &'a str, params: &'a QuestionParams) -> AskFuture<'a>;
+
+    /// Like `ask`, but passes the output text to `on_delta` as it arrives.
+    /// Clients that can't stream deliver the whole text at once.
+    fn ask_streaming<'a>(
+        &'a self,
+        question: &'a str,
+        params: &'a QuestionParams,
+        on_delta: OnDelta<'a>,
+    ) -> AskFuture<'a> {
+        Box::pin(async move {
+            let answer = self.ask(question, params).await?;
+            if let Some(text) = answer.to_string() {
+                on_delta(&text);
+            }
+            Ok(answer)
+        })
+    }
+
+    /// Cheap local check used by the pool before handing the client out.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
+
+pub type LlmBox = Box<dyn LlmClient>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LlmError {
+    #[error("rate limited")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("authentication failed ({0})")]
+    AuthFailed(u16),
+    #[error("bad request ({status}): {body}")]
+    BadRequest { status: u16, body: String },
+    #[error("server error ({status}): {body}")]
+    ServerError { status: u16, body: String, retry_after: Option<Duration> },
+    #[error("request timed out")]
+    Timeout,
+    #[error("transport error: {0}")]
+    Transport(String),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("circuit breaker is open")]
+    CircuitOpen,
+}
+
+impl LlmError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self,
+            LlmError::RateLimited { .. }
+            | LlmError::ServerError { .. }
+            | LlmError::Timeout
+            | LlmError::Transport(_))
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LlmError::RateLimited { retry_after } => *retry_after,
+            LlmError::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Finds the typed error behind an `LlmClient::ask` failure, if any.
+    pub fn of(e: &anyhow::Error) -> Option<&LlmError> {
+        e.downcast_ref::<LlmError>()
+    }
+
+    /// Whether the error says something about backend availability and
+    /// should count against the circuit breaker. Rate limits don't: most
+    /// come from our own key limits, and the rest are quota, not outages.
+    pub fn is_backend_failure(&self) -> bool {
+        self.is_retryable() && !matches!(self, LlmError::RateLimited { .. })
+    }
+
+    /// Whether the client that produced this error should not be reused.
+    pub fn breaks_client(&self) -> bool {
+        matches!(self, LlmError::AuthFailed(_) | LlmError::Transport(_))
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MockRule {
+    /// Case-insensitive substring of the question.
+    #[serde(rename = "match")]
+    pattern: String,
+    reply: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MockClient {
+    #[serde(default)]
+    rules: Vec<MockRule>,
+    default: String,
+}
+
+impl MockClient {
+    /// Rules good enough to play a whole game offline.
+    pub fn new() -> Self {
+        let rule = |pattern: &str, reply: &str| MockRule {
+            pattern: pattern.to_owned(),
+            reply: reply.to_owned(),
+        };
+        Self {
+            rules: vec![
+                rule("pick a random well-known", "Elephant"),
+                rule(r#"{"guess":"#, r#"{"verdict":"no"}"#),
+                rule("alive", r#"{"verdict":"yes","comment":"It is alive.","confidence":0.9}"#),
+                rule("animal", r#"{"verdict":"yes","comment":"It is an animal.","confidence":0.9}"#),
+            ],
+            default: r#"{"verdict":"no","comment":"Mock answer.","confidence":0.5}"#.to_owned(),
+        }
+    }
+
+    /// Loads `{"rules": [{"match": "...", "reply": "..."}], "default": "..."}`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading mock fixture at {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("parsing mock fixture at {}", path.display()))
+    }
+
+    fn reply(&self, question: &str) -> &str {
+        let question = question.to_lowercase();
+        self.rules
+            .iter()
+            .find(|r| question.contains(&r.pattern.to_lowercase()))
+            .map(|r| r.reply.as_str())
+            .unwrap_or(&self.default)
+    }
+}
+
+impl LlmClient for MockClient {
+    fn ask<'a>(&'a self, question: &'a str, _params: &'a QuestionParams) -> AskFuture<'a> {
+        let reply = self.reply(question).to_owned();
+        Box::pin(async move { Answer::from_text(&reply) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits_are_not_backend_failures() {
+        assert!(!LlmError::RateLimited { retry_after: None }.is_backend_failure());
+        assert!(LlmError::Timeout.is_backend_failure());
+        assert!(LlmError::ServerError { status: 502, body: String::new(), retry_after: None }.is_backend_failure());
+        assert!(!LlmError::BadRequest { status: 400, body: String::new() }.is_backend_failure());
+    }
+}
@@ -1,18 +1,18 @@
 #![allow(unused_imports)]
 
-#[macro_use]
-
 mod server;
 mod gpt;
+mod llm;
 
-use std::path::PathBuf;
 use std::sync::Arc;
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use crate::server::server::run_server;
-use crate::server::server::Config;
 use crate::server::client_pool::*;
-use crate::gpt::GptClient;
+use crate::gpt::{GptClient, GptClientConfig};
+use crate::llm::{LlmBox, MockClient};
+use crate::key_ring::KeyRing;
+use crate::config::{Options, Settings};
 use tracing_subscriber::EnvFilter;
 
 #[macro_use]
@@ -20,46 +20,73 @@ mod macros;
 mod utinls;
 mod token;
 mod game_manager;
+mod subject;
+mod usage;
+mod budget;
+mod key_ring;
+mod model_policy;
+mod events;
+mod game_store;
+mod config;
+#[cfg(test)]
+mod test_util;
 
-struct GptClientFactory {
+struct LlmClientFactory {
     config: ClientFactoryConfig,
+    gpt_config: GptClientConfig,
+    key_ring: Arc<KeyRing>,
+    /// When set, every pooled client is a copy of this mock instead of a GptClient.
+    mock: Option<MockClient>,
 }
 
-impl GptClientFactory {
-    fn new() -> GptClientFactory {
-        let mut res = Self {
-            config: ClientFactoryConfig::default()
-        };
-        res.config.max_clients = 5;
-        res
+impl LlmClientFactory {
+    fn new(config: ClientFactoryConfig, gpt_config: GptClientConfig, key_ring: Arc<KeyRing>,
+           mock: Option<MockClient>) -> LlmClientFactory {
+        Self {
+            config,
+            gpt_config,
+            key_ring,
+            mock,
+        }
     }
 }
 
 
-impl PollableClientFactory::<GptClient> for GptClientFactory {
-    fn build_client(&self) -> GptClient {
-        let mut cli = GptClient::new();
-        cli.read_gpt_key_from_file(None).expect("Can't read gpt API key");
-        cli
+impl PollableClientFactory::<LlmBox> for LlmClientFactory {
+    fn build_client(&self) -> LlmBox {
+        if let Some(mock) = &self.mock {
+            return Box::new(mock.clone());
+        }
+        let mut cli = GptClient::with_config(self.gpt_config.clone());
+        if let Some(binding) = self.key_ring.bind() {
+            cli.bind_key(binding);
+        }
+        Box::new(cli)
     }
 
     fn get_config(&self) -> &ClientFactoryConfig {
         &self.config
     }
-}
 
-fn www_root() -> PathBuf {
-    #[cfg(debug_assertions)]
-    {
-        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("www")
-    }
-    #[cfg(not(debug_assertions))]
-    {
-        PathBuf::from(std::env::var("WWW_ROOT")
-            .expect("WWW_ROOT env var must be set in release builds"))
+    fn is_healthy(&self, client: &LlmBox) -> bool {
+        client.is_healthy()
     }
 }
 
+fn key_ring(settings: &Settings) -> Result<KeyRing> {
+    // local servers and the mock usually need no key at all
+    let keys = match KeyRing::load_keys(settings.keys_file.as_deref()) {
+        Ok(keys) => keys,
+        Err(e) if settings.mock.is_some() || !settings.gpt.is_openai() => {
+            tracing::info!("no API keys loaded: {:#}", e);
+            Vec::new()
+        }
+        Err(e) => return Err(e.context("can't load gpt API keys")),
+    };
+    tracing::info!("loaded {} API key(s)", keys.len());
+    Ok(KeyRing::new(keys, settings.key_selection, settings.key_limits.clone()))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -69,10 +96,10 @@ async fn main() -> Result<()> {
         )
         .init();
 
-   let mut config = Config::default();
-    config.port = 3000;
-    config.www_root_path = Some(www_root());
-    run_server(&config, Arc::new(GptClientFactory::new())).await?;
+    let settings = Settings::resolve(Options::load()?).context("invalid configuration")?;
+    let key_ring = Arc::new(key_ring(&settings)?);
+    let factory = LlmClientFactory::new(settings.pool, settings.gpt, key_ring, settings.mock);
+    run_server(&settings.server, Arc::new(factory)).await?;
     Ok(())
 }
 
@@ -0,0 +1,111 @@
+#![allow(dead_code)]
+
+use std::str::FromStr;
+use anyhow::{Context, Result};
+use crate::gpt::Model;
+use crate::string_enum;
+
+string_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Task {
+        Answer => "answer",
+        Guess => "guess",
+        Subject => "subject",
+    }
+}
+
+/// Per task escalation chains. The first model is tried first; the next
+/// one is used when the reply is unusable.
+#[derive(Debug, Clone)]
+pub struct ModelPolicy {
+    answer: Vec<Model>,
+    guess: Vec<Model>,
+    subject: Vec<Model>,
+    /// Off when the server picks the model itself (`model_override`), as
+    /// switching models would only repeat the same request.
+    escalate: bool,
+}
+
+impl Default for ModelPolicy {
+    fn default() -> Self {
+        Self {
+            answer: vec![Model::Gpt5Nano, Model::Gpt5Mini, Model::Gpt5],
+            guess: vec![Model::Gpt5Mini, Model::Gpt5],
+            subject: vec![Model::Gpt5Mini],
+            escalate: true,
+        }
+    }
+}
+
+impl ModelPolicy {
+    pub fn chain(&self, task: Task) -> &[Model] {
+        match task {
+            Task::Answer => &self.answer,
+            Task::Guess => &self.guess,
+            Task::Subject => &self.subject,
+        }
+    }
+
+    pub fn escalates(&self) -> bool {
+        self.escalate
+    }
+
+    /// Only the first model of each chain, with no fallbacks.
+    pub fn without_escalation(mut self) -> Self {
+        self.escalate = false;
+        self
+    }
+}
+
+/// "answer=gpt-5-nano,gpt-5-mini;guess=gpt-5" - tasks left out keep their defaults.
+impl FromStr for ModelPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut policy = ModelPolicy::default();
+        for item in s.split(';').map(str::trim).filter(|i| !i.is_empty()) {
+            let (task, models) = item
+                .split_once('=')
+                .with_context(|| format!("'{}' is not in 'task=model,...' form", item))?;
+            let task = Task::from_str(task.trim())
+                .map_err(|_| anyhow::anyhow!("unknown task '{}'", task.trim()))?;
+            let chain = models
+                .split(',')
+                .map(|m| Model::from_str(m.trim())
+                    .map_err(|_| anyhow::anyhow!("unknown model '{}'", m.trim())))
+                .collect::<Result<Vec<Model>>>()?;
+            if chain.is_empty() {
+                anyhow::bail!("no models for task '{}'", task);
+            }
+            match task {
+                Task::Answer => policy.answer = chain,
+                Task::Guess => policy.guess = chain,
+                Task::Subject => policy.subject = chain,
+            }
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chains() {
+        let policy: ModelPolicy = "answer = gpt-5-mini, gpt-5 ; subject=gpt-5-nano".parse().unwrap();
+        assert_eq!(policy.chain(Task::Answer), [Model::Gpt5Mini, Model::Gpt5]);
+        assert_eq!(policy.chain(Task::Subject), [Model::Gpt5Nano]);
+        // left out, so the default
+        assert_eq!(policy.chain(Task::Guess), ModelPolicy::default().chain(Task::Guess));
+        assert!(policy.escalates());
+        assert!(!policy.without_escalation().escalates());
+    }
+
+    #[test]
+    fn rejects_bad_policies() {
+        for bad in ["answer", "question=gpt-5", "answer=gpt-4", "answer="] {
+            assert!(bad.parse::<ModelPolicy>().is_err(), "{}", bad);
+        }
+    }
+}
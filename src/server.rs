@@ -1,4 +1,9 @@
+#[allow(clippy::module_inception)]
 pub mod server;
 pub mod client_pool;
 pub mod answer_cache;
 pub mod error;
+pub mod circuit_breaker;
+pub mod listener;
+pub mod shutdown;
+pub mod rate_limit;
@@ -3,8 +3,12 @@ use std::time::Duration;
 use rand::Rng;
 use std::collections::VecDeque;
 use std::iter;
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
+use anyhow::{Context, Result};
 use linked_hash_map::LinkedHashMap;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Notify;
 use crate::utinls::*;
 
@@ -13,6 +17,8 @@ use crate::utinls::*;
 pub enum SlotState {
     Pending,
     Content(String),
+    /// No answer is coming; holds the reason.
+    Failed(String),
 }
 
 
@@ -31,6 +37,12 @@ impl Slot {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct SavedAnswer {
+    token: String,
+    answer: String,
+}
+
 #[derive(Default)]
 pub struct AnswerCache {
     map: LinkedHashMap<String, Slot>,
@@ -40,14 +52,57 @@ pub struct AnswerCache {
 pub enum AnswerCacheEntry {
     Text(String),
     Pending,
+    Failed(String),
     None,
 }
 
 impl AnswerCache {
     pub fn new() -> Self {
-        let mut res = Self::default();
-        res.limit = 2048;
-        res
+        Self::with_limit(2048)
+    }
+
+    /// Oldest tokens are dropped beyond `limit`.
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Answers written by `save`, oldest first.
+    pub fn load(path: &Path, limit: usize) -> Result<Self> {
+        let mut cache = Self::with_limit(limit);
+        if !path.exists() {
+            return Ok(cache);
+        }
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading answer cache at {}", path.display()))?;
+        let saved: Vec<SavedAnswer> = serde_json::from_str(&contents)
+            .with_context(|| format!("parsing answer cache at {}", path.display()))?;
+        for SavedAnswer { token, answer } in saved {
+            cache.map.insert(token, Slot { state: SlotState::Content(answer), ..Slot::new() });
+        }
+        while cache.map.len() > cache.limit {
+            cache.map.pop_front();
+        }
+        Ok(cache)
+    }
+
+    /// Writes the finished answers; pending ones are lost. Returns how many were written.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        let saved: Vec<SavedAnswer> = self.map
+            .iter()
+            .filter_map(|(token, slot)| match &slot.state {
+                SlotState::Content(answer) => Some(SavedAnswer { token: token.clone(), answer: answer.clone() }),
+                SlotState::Pending | SlotState::Failed(_) => None,
+            })
+            .collect();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&saved)?)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("renaming {} to {}", tmp.display(), path.display()))?;
+        Ok(saved.len())
     }
 
     pub fn reserve_token(&mut self) -> String {
@@ -64,6 +119,17 @@ impl AnswerCache {
     pub fn insert(&mut self, token: &str, text: &str) -> bool {
         if let Some(slot) = self.map.get_mut(token) {
             slot.state = SlotState::Content(text.to_owned());
+            slot.notify.notify_waiters();
+            return true;
+        }
+        false
+    }
+
+    /// Wakes the waiters of a slot that will never be filled.
+    pub fn fail(&mut self, token: &str, reason: &str) -> bool {
+        if let Some(slot) = self.map.get_mut(token) {
+            slot.state = SlotState::Failed(reason.to_owned());
+            slot.notify.notify_waiters();
             return true;
         }
         false
@@ -75,6 +141,7 @@ impl AnswerCache {
                 match &slot.state {
                     SlotState::Content(text) => AnswerCacheEntry::Text(text.to_string()),
                     SlotState::Pending => AnswerCacheEntry::Pending,
+                    SlotState::Failed(reason) => AnswerCacheEntry::Failed(reason.clone()),
                 }
             }
             None => AnswerCacheEntry::None,
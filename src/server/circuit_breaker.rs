@@ -0,0 +1,318 @@
+#![allow(dead_code)]
+
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+    /// Error rate is computed over calls within this window.
+    pub window: Duration,
+    /// Below this many calls in the window the breaker never opens.
+    pub min_calls: u32,
+    pub failure_rate: f64,
+    /// Calls slower than this count as failures.
+    pub slow_call: Duration,
+    pub open_duration: Duration,
+    /// Concurrent trial calls allowed while half-open.
+    pub half_open_probes: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            min_calls: 5,
+            failure_rate: 0.5,
+            slow_call: Duration::from_secs(30),
+            open_duration: Duration::from_secs(30),
+            half_open_probes: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: u32 },
+}
+
+impl BreakerState {
+    fn name(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    state: BreakerState,
+    window_start: Instant,
+    calls: u32,
+    failures: u32,
+    /// Bumped on every transition, so calls let through in an earlier
+    /// state can't drive the current one.
+    generation: u64,
+}
+
+/// Closed -> Open when too many calls in the window fail or are slow,
+/// Open -> HalfOpen after `open_duration`, HalfOpen -> Closed on a good
+/// probe or back to Open on a bad one.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    inner: StdMutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            inner: StdMutex::new(Inner {
+                state: BreakerState::Closed,
+                window_start: Instant::now(),
+                calls: 0,
+                failures: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn transition(&self, inner: &mut Inner, state: BreakerState) {
+        tracing::warn!("LLM circuit breaker {} -> {} ({} of {} calls failed)",
+            inner.state.name(), state.name(), inner.failures, inner.calls);
+        inner.state = state;
+        inner.window_start = Instant::now();
+        inner.calls = 0;
+        inner.failures = 0;
+        inner.generation += 1;
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        let until = Instant::now() + self.config.open_duration;
+        self.transition(inner, BreakerState::Open { until });
+    }
+
+    /// True while the breaker is open; doesn't use up a half-open probe.
+    pub fn is_open(&self) -> bool {
+        matches!(self.lock().state, BreakerState::Open { until } if until > Instant::now())
+    }
+
+    /// A permit when a call may go out now. The call's outcome is given to
+    /// `BreakerPermit::record`; a permit dropped without one counts as a
+    /// failure, so an abandoned probe can't hold the breaker half-open.
+    pub fn allow(&self) -> Option<BreakerPermit<'_>> {
+        let mut inner = self.lock();
+        match inner.state {
+            BreakerState::Closed => {}
+            BreakerState::Open { until } => {
+                if until > Instant::now() {
+                    return None;
+                }
+                self.transition(&mut inner, BreakerState::HalfOpen { in_flight: 1 });
+            }
+            BreakerState::HalfOpen { in_flight } => {
+                if in_flight >= self.config.half_open_probes {
+                    return None;
+                }
+                inner.state = BreakerState::HalfOpen { in_flight: in_flight + 1 };
+            }
+        }
+        Some(BreakerPermit { breaker: self, generation: inner.generation, recorded: false })
+    }
+
+    fn record(&self, generation: u64, success: bool, latency: Duration) {
+        let failed = !success || latency >= self.config.slow_call;
+        let mut inner = self.lock();
+        if generation != inner.generation {
+            // e.g. a call let through while closed that ends after the
+            // breaker went half-open; only that state's probes count
+            return;
+        }
+        match inner.state {
+            BreakerState::HalfOpen { in_flight } => {
+                if failed {
+                    self.open(&mut inner);
+                } else if in_flight <= 1 {
+                    self.transition(&mut inner, BreakerState::Closed);
+                } else {
+                    inner.state = BreakerState::HalfOpen { in_flight: in_flight - 1 };
+                }
+            }
+            BreakerState::Closed => {
+                if inner.window_start.elapsed() >= self.config.window {
+                    inner.window_start = Instant::now();
+                    inner.calls = 0;
+                    inner.failures = 0;
+                }
+                inner.calls += 1;
+                if failed {
+                    inner.failures += 1;
+                }
+                let rate = inner.failures as f64 / inner.calls as f64;
+                if inner.calls >= self.config.min_calls && rate >= self.config.failure_rate {
+                    self.open(&mut inner);
+                }
+            }
+            // no permits are given out while open
+            BreakerState::Open { .. } => {}
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let inner = self.lock();
+        json!({
+            "state": inner.state.name(),
+            "calls": inner.calls,
+            "failures": inner.failures,
+        })
+    }
+}
+
+/// One call let through by `CircuitBreaker::allow`.
+pub struct BreakerPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    generation: u64,
+    recorded: bool,
+}
+
+impl BreakerPermit<'_> {
+    pub fn record(mut self, success: bool, latency: Duration) {
+        self.recorded = true;
+        self.breaker.record(self.generation, success, latency);
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.record(self.generation, false, Duration::ZERO);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(1);
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            min_calls: 4,
+            failure_rate: 0.5,
+            slow_call: Duration::from_secs(1),
+            open_duration: Duration::from_millis(20),
+            ..BreakerConfig::default()
+        })
+    }
+
+    fn state(breaker: &CircuitBreaker) -> Value {
+        breaker.to_json()["state"].clone()
+    }
+
+    fn call(breaker: &CircuitBreaker, success: bool, latency: Duration) {
+        breaker.allow().expect("breaker should be closed").record(success, latency);
+    }
+
+    fn open(breaker: &CircuitBreaker) {
+        for _ in 0..4 {
+            call(breaker, false, FAST);
+        }
+        assert_eq!(state(breaker), "open");
+    }
+
+    fn wait_until_half_open(breaker: &CircuitBreaker) {
+        std::thread::sleep(breaker.config.open_duration);
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn opens_on_failure_rate() {
+        let breaker = breaker();
+        call(&breaker, true, FAST);
+        call(&breaker, false, FAST);
+        call(&breaker, true, FAST);
+        assert_eq!(state(&breaker), "closed");
+        call(&breaker, false, FAST);
+        assert_eq!(state(&breaker), "open");
+        assert!(breaker.is_open());
+        assert!(breaker.allow().is_none());
+    }
+
+    #[test]
+    fn few_calls_never_open() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            call(&breaker, false, FAST);
+        }
+        assert_eq!(state(&breaker), "closed");
+    }
+
+    #[test]
+    fn slow_calls_are_failures() {
+        let breaker = breaker();
+        for _ in 0..4 {
+            call(&breaker, true, Duration::from_secs(2));
+        }
+        assert_eq!(state(&breaker), "open");
+    }
+
+    #[test]
+    fn good_probe_closes() {
+        let breaker = breaker();
+        open(&breaker);
+        wait_until_half_open(&breaker);
+
+        let probe = breaker.allow().expect("one probe is let through");
+        assert_eq!(state(&breaker), "half_open");
+        assert!(breaker.allow().is_none());
+        probe.record(true, FAST);
+        assert_eq!(state(&breaker), "closed");
+    }
+
+    #[test]
+    fn bad_probe_reopens() {
+        let breaker = breaker();
+        open(&breaker);
+        wait_until_half_open(&breaker);
+
+        breaker.allow().unwrap().record(false, FAST);
+        assert_eq!(state(&breaker), "open");
+    }
+
+    #[test]
+    fn abandoned_probe_reopens() {
+        let breaker = breaker();
+        open(&breaker);
+        wait_until_half_open(&breaker);
+
+        drop(breaker.allow().unwrap());
+        assert_eq!(state(&breaker), "open");
+        wait_until_half_open(&breaker);
+        assert!(breaker.allow().is_some());
+    }
+
+    #[test]
+    fn calls_from_before_the_probe_are_ignored() {
+        let breaker = breaker();
+        let (early, late) = (breaker.allow().unwrap(), breaker.allow().unwrap());
+        open(&breaker);
+        wait_until_half_open(&breaker);
+
+        let probe = breaker.allow().unwrap();
+        early.record(true, FAST);
+        assert_eq!(state(&breaker), "half_open");
+        assert!(breaker.allow().is_none());
+        drop(late);
+        assert_eq!(state(&breaker), "half_open");
+        probe.record(true, FAST);
+        assert_eq!(state(&breaker), "closed");
+    }
+}
@@ -1,27 +1,83 @@
 #![allow(dead_code)]
 
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::Mutex as StdMutex;
-use crate::gpt::GptClient;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::sync::oneshot;
 
 #[derive(Clone, Default)]
 pub struct ClientFactoryConfig {
     pub max_clients: i32,
+    /// How many `acquire()` callers may wait for a client at once.
+    pub max_queue: usize,
+    pub acquire_timeout: Duration,
+    /// Clients older than this are rebuilt.
+    pub max_age: Option<Duration>,
+    /// Clients handed out this many times are rebuilt.
+    pub max_uses: Option<u32>,
+    /// Idle clients unused for this long are dropped by `shrink_idle()`.
+    pub idle_timeout: Option<Duration>,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct PoolStats {
+    pub clients_total: i32,
+    pub clients_idle: usize,
+    pub queue_depth: usize,
+    pub waits: u64,
+    pub timeouts: u64,
+    pub rejected: u64,
+    pub total_wait_ms: u64,
+    pub max_wait_ms: u64,
+    pub evicted: u64,
 }
 
 pub trait PollableClientFactory<Client> : Send + Sync {
     fn build_client(&self) -> Client;
     fn get_config(&self) -> &ClientFactoryConfig;
+
+    /// Checked before an idle client is handed out; unhealthy ones are rebuilt.
+    fn is_healthy(&self, _client: &Client) -> bool {
+        true
+    }
 }
 
 pub type Factory<Client> =
     Arc<dyn PollableClientFactory<Client> + Send + Sync>;
 
 
+struct PooledClient<Client> {
+    client: Arc<Client>,
+    created: Instant,
+    last_used: Instant,
+    uses: u32,
+}
+
+impl<Client> PooledClient<Client> {
+    fn new(client: Client) -> Self {
+        let now = Instant::now();
+        Self {
+            client: Arc::new(client),
+            created: now,
+            last_used: now,
+            uses: 0,
+        }
+    }
+
+    fn is_stale(&self, config: &ClientFactoryConfig) -> bool {
+        config.max_age.is_some_and(|age| self.created.elapsed() >= age)
+            || config.max_uses.is_some_and(|uses| self.uses >= uses)
+    }
+}
+
 
 struct ClientsStorage<Client> {
-    clients: Vec<Arc<Client>>,
+    clients: Vec<PooledClient<Client>>,
     clients_total: i32,
+    waiters: VecDeque<oneshot::Sender<PooledClient<Client>>>,
+    stats: PoolStats,
 }
 
 impl<Client> ClientsStorage<Client> {
@@ -29,8 +85,21 @@ impl<Client> ClientsStorage<Client> {
         Self {
             clients: Vec::new(),
             clients_total: 0,
+            waiters: VecDeque::new(),
+            stats: PoolStats::default(),
         }
     }
+
+    /// Drops waiters whose `acquire()` already gave up.
+    fn prune_waiters(&mut self) {
+        self.waiters.retain(|w| !w.is_closed());
+    }
+
+    fn evict(&mut self, client: PooledClient<Client>) {
+        drop(client);
+        self.clients_total -= 1;
+        self.stats.evicted += 1;
+    }
 }
 
 
@@ -39,16 +108,16 @@ pub struct ClientsPool<Client> {
     factory: Arc<dyn PollableClientFactory<Client> + Send + Sync>,
 }
 
-#[derive(Clone)]
 pub struct ClientGuard<Client> {
-    client: Option<Arc<Client>>,
+    client: Option<PooledClient<Client>>,
+    broken: bool,
     pool: Arc<ClientsPool<Client>>,
 }
 
 
 impl<Client> ClientGuard<Client> {
     pub fn client(&self) -> &Client {
-        self.client.as_ref().unwrap().as_ref()
+        self.client.as_ref().unwrap().client.as_ref()
     }
 
     pub fn has_client(&self) -> bool {
@@ -57,13 +126,18 @@ impl<Client> ClientGuard<Client> {
         }
         true
     }
+
+    /// The client is dropped instead of returned to the pool.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
 }
 
 impl<Client> Drop for ClientGuard<Client>
 {
      fn drop(&mut self) {
         if let Some(client) = self.client.take() {
-            self.pool.return_client(client);
+            self.pool.return_client(client, self.broken);
         }
     }
 }
@@ -72,37 +146,312 @@ impl<Client> ClientsPool<Client> {
     pub fn new(factory: Arc<dyn PollableClientFactory<Client> + Send + Sync>) -> Self {
         Self {
             storage: StdMutex::new(ClientsStorage::<Client>::new()),
-            factory: factory,
+            factory,
         }
     }
 
+    fn guard(self: &Arc<Self>, client: Option<PooledClient<Client>>) -> ClientGuard<Client> {
+        let client = client.map(|mut c| {
+            c.uses += 1;
+            c.last_used = Instant::now();
+            c
+        });
+        ClientGuard { client, broken: false, pool: Arc::clone(self) }
+    }
+
+    fn build(&self, storage: &mut ClientsStorage<Client>) -> PooledClient<Client> {
+        storage.clients_total += 1;
+        tracing::debug!("creating client {}", storage.clients_total);
+        PooledClient::new(self.factory.build_client())
+    }
+
+    /// Takes a healthy idle client or builds a new one if the pool isn't full yet.
+    fn try_take(&self, storage: &mut ClientsStorage<Client>) -> Option<PooledClient<Client>> {
+        let config = self.factory.get_config();
+        while let Some(client) = storage.clients.pop() {
+            if !client.is_stale(config) && self.factory.is_healthy(&client.client) {
+                return Some(client);
+            }
+            tracing::debug!("evicting stale or unhealthy client");
+            storage.evict(client);
+        }
+        if storage.clients_total >= config.max_clients {
+            return None;
+        }
+        Some(self.build(storage))
+    }
+
+    /// Returns immediately; the guard is empty when no client is free.
     pub fn pop(self: &Arc<Self>) -> ClientGuard<Client> {
         let mut storage = self.storage.lock().unwrap();
+        // don't jump the queue
+        storage.prune_waiters();
+        if !storage.waiters.is_empty() {
+            return self.guard(None);
+        }
+        let client = self.try_take(&mut storage);
+        self.guard(client)
+    }
+
+    /// Waits in FIFO order for a client. The guard is empty when the queue
+    /// is full or the configured timeout expires.
+    pub async fn acquire(self: &Arc<Self>) -> ClientGuard<Client> {
         let config = self.factory.get_config();
-        if storage.clients.len() == 0 {
-            if storage.clients_total >= config.max_clients {
-                return ClientGuard {
-                    client: None,
-                    pool: Arc::clone(self)
+        let mut rx = {
+            let mut storage = self.storage.lock().unwrap();
+            storage.prune_waiters();
+            if storage.waiters.is_empty() {
+                if let Some(client) = self.try_take(&mut storage) {
+                    return self.guard(Some(client));
                 }
             }
-            storage.clients_total += 1;
-            println!("creating client {}", storage.clients_total);
-            return ClientGuard {
-                client: Some(Arc::new(self.factory.build_client())),
-                pool: Arc::clone(self)
-            };
+            if storage.waiters.len() >= config.max_queue {
+                storage.stats.rejected += 1;
+                return self.guard(None);
+            }
+            let (tx, rx) = oneshot::channel();
+            storage.waiters.push_back(tx);
+            rx
+        };
+
+        let start = Instant::now();
+        let client = match tokio::time::timeout(config.acquire_timeout, &mut rx).await {
+            Ok(Ok(client)) => Some(client),
+            _ => {
+                // a client may have been sent right before we gave up
+                rx.close();
+                rx.try_recv().ok()
+            }
+        };
+
+        let waited = start.elapsed().as_millis() as u64;
+        if let Ok(mut storage) = self.storage.lock() {
+            let stats = &mut storage.stats;
+            stats.waits += 1;
+            stats.total_wait_ms += waited;
+            stats.max_wait_ms = stats.max_wait_ms.max(waited);
+            if client.is_none() {
+                stats.timeouts += 1;
+            }
+        }
+        self.guard(client)
+    }
+
+    /// Hands the client to the longest waiting `acquire()` or puts it back.
+    /// Broken or stale clients are dropped, and a replacement is built only
+    /// when somebody is waiting for it.
+    fn return_client(&self, client: PooledClient<Client>, broken: bool) {
+        let Ok(mut storage) = self.storage.lock() else {
+            return;
+        };
+        storage.prune_waiters();
+
+        let mut client = if broken || client.is_stale(self.factory.get_config()) {
+            storage.evict(client);
+            if storage.waiters.is_empty() {
+                return;
+            }
+            self.build(&mut storage)
         } else {
-            println!("using free client; there are {}", storage.clients.len());
+            client
+        };
+
+        while let Some(waiter) = storage.waiters.pop_front() {
+            match waiter.send(client) {
+                Ok(()) => return,
+                Err(returned) => client = returned,
+            }
         }
-        let client = storage.clients.pop().unwrap();
-        ClientGuard { client: Some(client), pool: Arc::clone(self) }
+        storage.clients.push(client);
     }
 
-    pub fn return_client(&self, client: Arc<Client>) {
+    /// Drops idle clients unused for longer than `idle_timeout`.
+    pub fn shrink_idle(&self) {
+        let Some(idle_timeout) = self.factory.get_config().idle_timeout else {
+            return;
+        };
+        let mut storage = self.storage.lock().unwrap_or_else(|e| e.into_inner());
+        let (idle, keep): (Vec<_>, Vec<_>) = std::mem::take(&mut storage.clients)
+            .into_iter()
+            .partition(|c| c.last_used.elapsed() >= idle_timeout);
+        storage.clients = keep;
+        for client in idle {
+            storage.evict(client);
+        }
+    }
 
-        if let Ok(mut storage) = self.storage.lock() {
-            storage.clients.push(client);
+    pub fn stats(&self) -> PoolStats {
+        let mut storage = self.storage.lock().unwrap_or_else(|e| e.into_inner());
+        storage.prune_waiters();
+        PoolStats {
+            clients_total: storage.clients_total,
+            clients_idle: storage.clients.len(),
+            queue_depth: storage.waiters.len(),
+            ..storage.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Clients are numbered in the order they are built; the ones in
+    /// `sick` fail the health check.
+    struct Numbered {
+        config: ClientFactoryConfig,
+        built: AtomicU32,
+        sick: StdMutex<Vec<u32>>,
+    }
+
+    impl PollableClientFactory<u32> for Numbered {
+        fn build_client(&self) -> u32 {
+            self.built.fetch_add(1, Ordering::SeqCst)
+        }
+
+        fn get_config(&self) -> &ClientFactoryConfig {
+            &self.config
+        }
+
+        fn is_healthy(&self, client: &u32) -> bool {
+            !self.sick.lock().unwrap().contains(client)
+        }
+    }
+
+    fn factory(config: ClientFactoryConfig) -> Arc<Numbered> {
+        Arc::new(Numbered { config, built: AtomicU32::new(0), sick: StdMutex::default() })
+    }
+
+    fn pool(max_clients: i32, max_queue: usize, acquire_timeout: Duration) -> Arc<ClientsPool<u32>> {
+        let config = ClientFactoryConfig { max_clients, max_queue, acquire_timeout, ..Default::default() };
+        Arc::new(ClientsPool::new(factory(config)))
+    }
+
+    fn config(max_clients: i32) -> ClientFactoryConfig {
+        ClientFactoryConfig { max_clients, max_queue: 1, acquire_timeout: Duration::from_secs(5), ..Default::default() }
+    }
+
+    async fn next_client(pool: &Arc<ClientsPool<u32>>) -> u32 {
+        *pool.acquire().await.client()
+    }
+
+    async fn wait_for_queue(pool: &Arc<ClientsPool<u32>>, depth: usize) {
+        while pool.stats().queue_depth < depth {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn waiters_are_served_in_order() {
+        let pool = pool(1, 4, Duration::from_secs(5));
+        let held = pool.acquire().await;
+        assert!(held.has_client());
+
+        let order = Arc::new(StdMutex::new(Vec::new()));
+        let mut waiters = Vec::new();
+        for (n, name) in ["first", "second", "third"].into_iter().enumerate() {
+            let (pool2, order) = (pool.clone(), order.clone());
+            waiters.push(tokio::spawn(async move {
+                let guard = pool2.acquire().await;
+                assert_eq!(*guard.client(), 0);
+                order.lock().unwrap().push(name);
+            }));
+            wait_for_queue(&pool, n + 1).await;
+        }
+        // an idle client must not let `pop` jump the queue
+        assert!(!pool.pop().has_client());
+
+        drop(held);
+        for waiter in waiters {
+            waiter.await.unwrap();
         }
+        assert_eq!(*order.lock().unwrap(), ["first", "second", "third"]);
+        let stats = pool.stats();
+        assert_eq!((stats.clients_total, stats.waits, stats.timeouts), (1, 3, 0));
+    }
+
+    #[tokio::test]
+    async fn waiting_times_out() {
+        let pool = pool(1, 4, Duration::from_millis(20));
+        let _held = pool.acquire().await;
+        assert!(!pool.acquire().await.has_client());
+        let stats = pool.stats();
+        assert_eq!((stats.waits, stats.timeouts, stats.queue_depth), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_rejected() {
+        let pool = pool(1, 1, Duration::from_secs(5));
+        let held = pool.acquire().await;
+        let waiter = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.acquire().await.has_client() })
+        };
+        wait_for_queue(&pool, 1).await;
+
+        assert!(!pool.acquire().await.has_client());
+        assert_eq!(pool.stats().rejected, 1);
+        drop(held);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn broken_clients_are_replaced() {
+        let pool = pool(1, 1, Duration::from_secs(5));
+        let mut held = pool.acquire().await;
+        held.mark_broken();
+        drop(held);
+        assert_eq!(*pool.acquire().await.client(), 1);
+        assert_eq!(pool.stats().evicted, 1);
+    }
+
+    #[tokio::test]
+    async fn used_up_clients_are_retired() {
+        let pool = Arc::new(ClientsPool::new(factory(ClientFactoryConfig { max_uses: Some(2), ..config(1) })));
+        assert_eq!(next_client(&pool).await, 0);
+        assert_eq!(next_client(&pool).await, 0);
+        // nobody was waiting, so no replacement yet
+        let stats = pool.stats();
+        assert_eq!((stats.evicted, stats.clients_total, stats.clients_idle), (1, 0, 0));
+        assert_eq!(next_client(&pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn old_clients_are_retired() {
+        let max_age = Duration::from_millis(20);
+        let pool = Arc::new(ClientsPool::new(factory(ClientFactoryConfig { max_age: Some(max_age), ..config(1) })));
+        assert_eq!(next_client(&pool).await, 0);
+        assert_eq!(next_client(&pool).await, 0);
+        tokio::time::sleep(max_age).await;
+        assert_eq!(next_client(&pool).await, 1);
+        assert_eq!(pool.stats().evicted, 1);
+    }
+
+    #[tokio::test]
+    async fn idle_clients_are_shrunk() {
+        let idle_timeout = Duration::from_millis(20);
+        let pool = Arc::new(ClientsPool::new(factory(ClientFactoryConfig { idle_timeout: Some(idle_timeout), ..config(2) })));
+        let (a, b) = (pool.acquire().await, pool.acquire().await);
+        drop((a, b));
+        tokio::time::sleep(idle_timeout).await;
+        // uses the last one returned and leaves the other idle
+        assert_eq!(next_client(&pool).await, 1);
+
+        pool.shrink_idle();
+        let stats = pool.stats();
+        assert_eq!((stats.evicted, stats.clients_total, stats.clients_idle), (1, 1, 1));
+        assert_eq!(next_client(&pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn unhealthy_clients_are_evicted() {
+        let factory = factory(config(1));
+        let pool = Arc::new(ClientsPool::new(factory.clone() as Factory<u32>));
+        assert_eq!(next_client(&pool).await, 0);
+        factory.sick.lock().unwrap().push(0);
+        assert_eq!(next_client(&pool).await, 1);
+        let stats = pool.stats();
+        assert_eq!((stats.evicted, stats.clients_total), (1, 1));
     }
 }
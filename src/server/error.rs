@@ -1,52 +1,224 @@
-#![allow(dead_code)]
+use std::time::Duration;
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::{json, Value};
+use crate::utinls::token_generator;
 
-use serde_json::json;
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
 
-pub struct Er{}
+/// Every way an API call can fail. `code()` is the stable `status` clients
+/// match on; the message is for humans and may change.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("the token is malformed")]
+    InvalidToken,
+    #[error("the request is invalid")]
+    InvalidRequest,
+    #[error("there is no such game")]
+    GameDoesNotExist,
+    /// Same status as `GameDoesNotExist`, with a hint that it timed out.
+    #[error("the game expired after a period of inactivity")]
+    GameExpired,
+    #[error("there is nothing here")]
+    NotFound,
+    #[error("a valid admin token is required")]
+    Unauthorized,
+    #[error("the previous question or guess is still being answered")]
+    Pending,
+    #[error("the game is over")]
+    GameOver,
+    #[error("all questions have been used")]
+    NoQuestionsLeft,
+    #[error("too many requests")]
+    RateLimited { retry_after: Duration },
+    #[error("the spending limit has been reached")]
+    BudgetExhausted,
+    #[error("the server is too busy")]
+    Overloaded,
+    #[error("answers are unavailable at the moment")]
+    Unavailable,
+    /// Shutting down; try again in a moment.
+    #[error("the server is restarting")]
+    Restarting,
+}
 
-impl Er{
-    fn error(message: &str) -> String {
-        json!({
-            "status": "error",
-            "message": message
-        }).to_string()
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::InvalidToken => "invalid_token",
+            AppError::InvalidRequest => "invalid_request",
+            AppError::GameDoesNotExist | AppError::GameExpired => "game_does_not_exist",
+            AppError::NotFound => "not_found",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Pending => "pending",
+            AppError::GameOver => "game_over",
+            AppError::NoQuestionsLeft => "no_questions_left",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::BudgetExhausted => "budget_exhausted",
+            AppError::Overloaded => "overloaded",
+            AppError::Unavailable => "answers_unavailable",
+            AppError::Restarting => "server_restarting",
+        }
     }
 
-    fn status(status: &str) -> String {
-        json!({
-            "status": status,
-        }).to_string()
+    pub fn http_status(&self) -> StatusCode {
+        match self {
+            AppError::InvalidToken | AppError::InvalidRequest => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::GameDoesNotExist | AppError::GameExpired | AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Pending | AppError::GameOver | AppError::NoQuestionsLeft => StatusCode::CONFLICT,
+            AppError::RateLimited { .. } | AppError::BudgetExhausted => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Overloaded | AppError::Unavailable | AppError::Restarting => StatusCode::SERVICE_UNAVAILABLE,
+        }
     }
-}
 
-pub enum ErStatus {
-    Pending,
-    InvalidToken,
-    Overloaded,
-    GameDoesNotExist,
-    InvalidRequest,
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            // whole seconds, rounded up so clients don't retry too early
+            AppError::RateLimited { retry_after } => Some(retry_after.as_millis().div_ceil(1000) as u64),
+            AppError::Overloaded | AppError::Restarting => Some(1),
+            _ => None,
+        }
+    }
+
+    /// The error body; also sent over the WebSocket.
+    pub fn to_json(&self) -> Value {
+        let mut body = json!({
+            "status": self.code(),
+            "message": self.to_string(),
+        });
+        if let AppError::GameExpired = self {
+            body["hint"] = "expired".into();
+        }
+        if let AppError::RateLimited { .. } = self {
+            body["retry_after"] = self.retry_after().into();
+        }
+        if let Some(id) = current_request_id() {
+            body["request_id"] = id.into();
+        }
+        body
+    }
 }
 
-impl ErStatus {
-    pub fn json(&self) -> String {
-        match self {
-            ErStatus::Pending => Er::status("pending"),
-            ErStatus::InvalidToken => Er::status("invalid_token"),
-            ErStatus::Overloaded => Er::status("overloaded"),
-            ErStatus::GameDoesNotExist => Er::status("game_does_not_exist"),
-            ErStatus::InvalidRequest => Er::status("invalid_request"),
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut res = (self.http_status(), axum::Json(self.to_json())).into_response();
+        if let Some(secs) = self.retry_after() {
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
         }
+        res
     }
 }
 
+fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
 
+/// Tags each request with an id, taken from the proxy's `X-Request-Id` if
+/// it looks sane, and echoes it back so error reports can be matched to logs.
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 64 && v.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_owned)
+        .unwrap_or_else(token_generator::generate_token);
+    // for the access log span
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        req.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    let mut res = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    res
+}
 
-#[derive(Debug, thiserror::Error)]
-pub enum AppError {
-    #[error("invalid token")]
-    InvalidToken,
-    #[error("game not found")]
-    GameNotFound,
-    #[error(transparent)]
-    Any(#[from] anyhow::Error),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    /// Every variant with the status and code clients rely on.
+    fn contract() -> Vec<(AppError, StatusCode, &'static str)> {
+        vec![
+            (AppError::InvalidToken, StatusCode::BAD_REQUEST, "invalid_token"),
+            (AppError::InvalidRequest, StatusCode::BAD_REQUEST, "invalid_request"),
+            (AppError::GameDoesNotExist, StatusCode::NOT_FOUND, "game_does_not_exist"),
+            (AppError::GameExpired, StatusCode::NOT_FOUND, "game_does_not_exist"),
+            (AppError::NotFound, StatusCode::NOT_FOUND, "not_found"),
+            (AppError::Unauthorized, StatusCode::UNAUTHORIZED, "unauthorized"),
+            (AppError::Pending, StatusCode::CONFLICT, "pending"),
+            (AppError::GameOver, StatusCode::CONFLICT, "game_over"),
+            (AppError::NoQuestionsLeft, StatusCode::CONFLICT, "no_questions_left"),
+            (AppError::RateLimited { retry_after: Duration::from_millis(2500) }, StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            (AppError::BudgetExhausted, StatusCode::TOO_MANY_REQUESTS, "budget_exhausted"),
+            (AppError::Overloaded, StatusCode::SERVICE_UNAVAILABLE, "overloaded"),
+            (AppError::Unavailable, StatusCode::SERVICE_UNAVAILABLE, "answers_unavailable"),
+            (AppError::Restarting, StatusCode::SERVICE_UNAVAILABLE, "server_restarting"),
+        ]
+    }
+
+    #[tokio::test]
+    async fn errors_keep_their_status_and_code() {
+        let app = Router::new()
+            .route("/{n}", get(|axum::extract::Path(n): axum::extract::Path<usize>| async move {
+                contract().swap_remove(n).0
+            }))
+            .layer(axum::middleware::from_fn(request_id));
+
+        for (n, (error, status, code)) in contract().into_iter().enumerate() {
+            let req = Request::builder()
+                .uri(format!("/{}", n))
+                .header(REQUEST_ID_HEADER, format!("req-{}", n))
+                .body(Body::empty())
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), status, "{}", code);
+            assert_eq!(res.headers()[REQUEST_ID_HEADER], format!("req-{}", n).as_str());
+            let retry_after = res.headers().get(header::RETRY_AFTER).map(|v| v.to_str().unwrap().to_owned());
+            let body: Value = serde_json::from_slice(&to_bytes(res.into_body(), 4096).await.unwrap()).unwrap();
+            assert_eq!(body["status"], code);
+            assert_eq!(body["message"], error.to_string());
+            assert_eq!(body["request_id"], format!("req-{}", n));
+            match error {
+                AppError::RateLimited { .. } => {
+                    assert_eq!(retry_after.as_deref(), Some("3"));
+                    assert_eq!(body["retry_after"], 3);
+                }
+                AppError::Overloaded | AppError::Restarting => assert_eq!(retry_after.as_deref(), Some("1")),
+                AppError::GameExpired => assert_eq!(body["hint"], "expired"),
+                _ => assert_eq!(retry_after, None),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_generated_when_missing_or_odd() {
+        let app = Router::new()
+            .route("/", get(|| async { AppError::NotFound }))
+            .layer(axum::middleware::from_fn(request_id));
+        for sent in [None, Some("has spaces"), Some("")] {
+            let mut req = Request::builder().uri("/");
+            if let Some(id) = sent {
+                req = req.header(REQUEST_ID_HEADER, id);
+            }
+            let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+            let id = res.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_owned();
+            assert!(token_generator::is_token(&id), "{}", id);
+            let body: Value = serde_json::from_slice(&to_bytes(res.into_body(), 4096).await.unwrap()).unwrap();
+            assert_eq!(body["request_id"], id);
+        }
+        // outside a request, e.g. over the WebSocket after the upgrade
+        assert!(AppError::Pending.to_json().get("request_id").is_none());
+    }
 }
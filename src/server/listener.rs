@@ -0,0 +1,255 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex as StdMutex, RwLock as StdRwLock};
+use std::time::{Duration, SystemTime};
+use anyhow::{Context, Result};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::serve::Listener;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use crate::server::error::AppError;
+
+/// Clients that don't finish the TLS handshake in time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Owner and group only; put the proxy in the socket's group.
+const UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Where the server accepts connections.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Tls(SocketAddr),
+    /// For running behind a reverse proxy on the same host.
+    Unix(PathBuf),
+}
+
+/// "127.0.0.1:3000", "[::]:3000", "tls://0.0.0.0:443" or "unix:/run/gggame.sock".
+impl FromStr for Listen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                anyhow::bail!("unix listener needs a path, got '{}'", s);
+            }
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        let (tls, addr) = match s.strip_prefix("tls://") {
+            Some(addr) => (true, addr),
+            None => (false, s.strip_prefix("tcp://").unwrap_or(s)),
+        };
+        let addr = addr.parse::<SocketAddr>()
+            .map_err(|_| anyhow::anyhow!("listen address must be 'host:port', 'tls://host:port' or 'unix:<path>', got '{}'", s))?;
+        Ok(if tls { Listen::Tls(addr) } else { Listen::Tcp(addr) })
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "http://{}", addr),
+            Listen::Tls(addr) => write!(f, "https://{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: PathBuf,
+    /// PEM private key.
+    pub key: PathBuf,
+    /// How often the files are checked for changes.
+    pub reload_interval: Duration,
+}
+
+/// The rustls config, rebuilt whenever the cert or key file changes so
+/// renewed certificates are picked up without a restart. Connections
+/// already established keep the old one.
+pub struct TlsReloader {
+    files: TlsConfig,
+    config: StdRwLock<Arc<ServerConfig>>,
+    modified: StdMutex<Option<SystemTime>>,
+}
+
+impl TlsReloader {
+    pub fn load(files: &TlsConfig) -> Result<Arc<Self>> {
+        let modified = Self::modified(files);
+        let config = Self::build(files)?;
+        Ok(Arc::new(Self {
+            files: files.clone(),
+            config: StdRwLock::new(Arc::new(config)),
+            modified: StdMutex::new(modified),
+        }))
+    }
+
+    fn build(files: &TlsConfig) -> Result<ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(&files.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("reading TLS certificate {}", files.cert.display()))?;
+        if certs.is_empty() {
+            anyhow::bail!("no certificates in {}", files.cert.display());
+        }
+        let key = PrivateKeyDer::from_pem_file(&files.key)
+            .with_context(|| format!("reading TLS key {}", files.key.display()))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("TLS key doesn't match the certificate")?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    /// The later of the two modification times.
+    fn modified(files: &TlsConfig) -> Option<SystemTime> {
+        let cert = fs::metadata(&files.cert).and_then(|m| m.modified()).ok()?;
+        let key = fs::metadata(&files.key).and_then(|m| m.modified()).ok()?;
+        Some(cert.max(key))
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// A broken or half written pair keeps the old config in place.
+    pub fn reload_if_changed(&self) {
+        let modified = Self::modified(&self.files);
+        {
+            let mut last = self.modified.lock().unwrap_or_else(|e| e.into_inner());
+            if modified.is_none() || *last == modified {
+                return;
+            }
+            *last = modified;
+        }
+        match Self::build(&self.files) {
+            Ok(config) => {
+                *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+                tracing::info!("reloaded TLS certificate {}", self.files.cert.display());
+            }
+            Err(e) => tracing::warn!("keeping the old TLS certificate: {:#}", e),
+        }
+    }
+
+    pub fn spawn_watcher(self: &Arc<Self>) {
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reloader.files.reload_interval.max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                reloader.reload_if_changed();
+            }
+        });
+    }
+}
+
+/// Accepts TCP connections and hands out the ones that completed the TLS
+/// handshake. Handshakes run in their own tasks so a slow client doesn't
+/// hold up the others.
+pub struct TlsListener {
+    rx: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub async fn bind(addr: SocketAddr, tls: Arc<TlsReloader>) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await
+            .with_context(|| format!("binding {}", addr))?;
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        // usually out of file descriptors, give it a moment
+                        tracing::warn!("accept on {} failed: {}", local_addr, e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let acceptor = TlsAcceptor::from(tls.current());
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, peer)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => tracing::debug!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        Ok(Self { rx, local_addr })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.rx.recv().await {
+            Some(conn) => conn,
+            // the accept task only stops once we're gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Binds `path`, replacing a socket left behind by a previous run.
+pub fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        fs::remove_file(path).with_context(|| format!("removing stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("binding {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(UNIX_SOCKET_MODE))
+        .with_context(|| format!("setting permissions on {}", path.display()))?;
+    Ok(listener)
+}
+
+/// Unix socket connections have no peer address. With `trust_real_ip` the
+/// proxy must pass the client in `X-Real-IP`; otherwise every request is
+/// from a local process and the header is ignored, which config only allows
+/// when nothing is limited per client.
+pub async fn unix_peer(State(trust_real_ip): State<bool>, mut req: Request, next: Next) -> Response {
+    let ip = if trust_real_ip {
+        let real_ip = req.headers()
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<IpAddr>().ok());
+        match real_ip {
+            Some(ip) => ip,
+            None => return AppError::InvalidRequest.into_response(),
+        }
+    } else {
+        IpAddr::from([127, 0, 0, 1])
+    };
+    req.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip, 0)));
+    next.run(req).await
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use futures_util::future::{self, Either, Ready};
+use tower::{Layer, Service};
+use crate::server::error::AppError;
+use crate::string_enum;
+
+string_enum! {
+    /// Routes limited together; `read` covers game state, versions,
+    /// events and answers.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum RouteClass {
+        Token => "token",
+        NewGame => "new_game",
+        Ask => "ask",
+        Guess => "guess",
+        Read => "read",
+    }
+}
+
+/// A bucket of `burst` requests that refills completely over `per`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+/// "10/min", "2/s", "100/h".
+impl FromStr for Limit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (burst, per) = s.split_once('/')
+            .with_context(|| format!("'{}' is not in 'count/unit' form", s))?;
+        let burst = burst.trim().parse::<u32>()
+            .ok()
+            .filter(|&b| b > 0)
+            .with_context(|| format!("bad request count in '{}'", s))?;
+        let per = match per.trim() {
+            "s" | "sec" => Duration::from_secs(1),
+            "m" | "min" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            other => anyhow::bail!("unknown unit '{}', use s, min or h", other),
+        };
+        Ok(Limit { burst, per })
+    }
+}
+
+/// Per client limits for each kind of route; routes without one are not limited.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    limits: HashMap<RouteClass, Limit>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let limit = |burst, secs| Limit { burst, per: Duration::from_secs(secs) };
+        Self {
+            limits: HashMap::from([
+                (RouteClass::Token, limit(60, 60)),
+                (RouteClass::NewGame, limit(10, 60)),
+                (RouteClass::Ask, limit(30, 60)),
+                (RouteClass::Guess, limit(10, 60)),
+                (RouteClass::Read, limit(600, 60)),
+            ]),
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn get(&self, route: RouteClass) -> Option<Limit> {
+        self.limits.get(&route).copied()
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.limits.is_empty()
+    }
+}
+
+/// "new_game=5/min;ask=off" - routes left out keep their defaults.
+impl FromStr for RateLimits {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut limits = RateLimits::default();
+        for item in s.split(';').map(str::trim).filter(|i| !i.is_empty()) {
+            let (route, limit) = item
+                .split_once('=')
+                .with_context(|| format!("'{}' is not in 'route=count/unit' form", item))?;
+            let route = RouteClass::from_str(route.trim())
+                .map_err(|_| anyhow::anyhow!("unknown route '{}'", route.trim()))?;
+            match limit.trim() {
+                "off" => { limits.limits.remove(&route); }
+                limit => { limits.limits.insert(route, limit.parse()?); }
+            }
+        }
+        Ok(limits)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per route and client.
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: DashMap<(RouteClass, IpAddr), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self { limits, buckets: DashMap::new() }
+    }
+
+    /// Takes a token, or says how long until one is available.
+    pub fn check(&self, route: RouteClass, ip: IpAddr) -> Result<(), Duration> {
+        let Some(limit) = self.limits.get(route) else {
+            return Ok(());
+        };
+        let rate = limit.burst as f64 / limit.per.as_secs_f64();
+        let now = Instant::now();
+        let mut bucket = self.buckets
+            .entry((route, client_key(ip)))
+            .or_insert_with(|| Bucket { tokens: limit.burst as f64, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(limit.burst as f64);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Forgets clients whose buckets have refilled.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|(route, _), bucket| match self.limits.get(*route) {
+            Some(limit) => now.duration_since(bucket.updated) < limit.per,
+            None => false,
+        });
+    }
+
+    pub fn layer(self: &Arc<Self>, route: RouteClass) -> RateLimitLayer {
+        RateLimitLayer { limiter: self.clone(), route }
+    }
+}
+
+/// IPv6 clients usually get a whole /64, so they share one bucket.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let bits = u128::from(v6) & !((1u128 << 64) - 1);
+                IpAddr::V6(Ipv6Addr::from(bits))
+            }
+        },
+        v4 => v4,
+    }
+}
+
+/// Rejects requests over the route's limit with a 429 `rate_limited` error.
+/// The client is whoever `ConnectInfo` says it is.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    route: RouteClass,
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService { inner, limiter: self.limiter.clone(), route: self.route }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    route: RouteClass,
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible>,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Either<Ready<Result<Response, Infallible>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let ip = req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        if let Some(ip) = ip {
+            if let Err(retry_after) = self.limiter.check(self.route, ip) {
+                let res = AppError::RateLimited { retry_after }.into_response();
+                return Either::Left(future::ready(Ok(res)));
+            }
+        }
+        Either::Right(self.inner.call(req))
+    }
+}
+
+/// Behind a trusted proxy the client is the last `X-Forwarded-For` hop
+/// that isn't one of our proxies.
+pub async fn forwarded_peer(State(trusted): State<Arc<Vec<IpAddr>>>, mut req: Request, next: Next) -> Response {
+    let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+        return next.run(req).await;
+    };
+    let mut client = peer.ip();
+    if trusted.contains(&client) {
+        let hops = req.headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|hop| hop.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop;
+            if !trusted.contains(&hop) {
+                break;
+            }
+        }
+    }
+    req.extensions_mut().insert(ConnectInfo(SocketAddr::new(client, peer.port())));
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn limits_parse() {
+        assert_eq!("10/min".parse::<Limit>().unwrap(), Limit { burst: 10, per: Duration::from_secs(60) });
+        assert_eq!(" 2 / s ".parse::<Limit>().unwrap(), Limit { burst: 2, per: Duration::from_secs(1) });
+        assert_eq!("100/hour".parse::<Limit>().unwrap().per, Duration::from_secs(3600));
+        for bad in ["10", "0/min", "-1/min", "ten/min", "10/day"] {
+            assert!(bad.parse::<Limit>().is_err(), "{}", bad);
+        }
+
+        let limits = "new_game=5/min; ask=off;".parse::<RateLimits>().unwrap();
+        assert_eq!(limits.get(RouteClass::NewGame), Some(Limit { burst: 5, per: Duration::from_secs(60) }));
+        assert_eq!(limits.get(RouteClass::Ask), None);
+        assert_eq!(limits.get(RouteClass::Guess), RateLimits::default().get(RouteClass::Guess));
+        assert_eq!("".parse::<RateLimits>().unwrap(), RateLimits::default());
+        for bad in ["ask", "chat=5/min", "ask=5"] {
+            assert!(bad.parse::<RateLimits>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn buckets_run_out() {
+        let limiter = RateLimiter::new("ask=2/min;read=off".parse().unwrap());
+        let client = ip("192.0.2.1");
+        assert!(limiter.check(RouteClass::Ask, client).is_ok());
+        assert!(limiter.check(RouteClass::Ask, client).is_ok());
+        let retry_after = limiter.check(RouteClass::Ask, client).unwrap_err();
+        assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+        assert!(limiter.check(RouteClass::Ask, ip("192.0.2.2")).is_ok());
+        for _ in 0..1000 {
+            assert!(limiter.check(RouteClass::Read, client).is_ok());
+        }
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        assert_eq!(client_key(ip("2001:db8:1:2:aaaa::1")), ip("2001:db8:1:2::"));
+        assert_eq!(client_key(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(client_key(ip("192.0.2.1")), ip("192.0.2.1"));
+
+        let limiter = RateLimiter::new("guess=1/min".parse().unwrap());
+        assert!(limiter.check(RouteClass::Guess, ip("2001:db8:1:2::1")).is_ok());
+        assert!(limiter.check(RouteClass::Guess, ip("2001:db8:1:2::2")).is_err());
+        assert!(limiter.check(RouteClass::Guess, ip("2001:db8:1:3::1")).is_ok());
+    }
+
+    /// The client address `forwarded_peer` settles on for a request from
+    /// `peer` carrying these `X-Forwarded-For` headers.
+    async fn client_of(peer: &str, forwarded_for: &[&str]) -> String {
+        let trusted = Arc::new(vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+        let app = Router::new()
+            .route("/", get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.ip().to_string() }))
+            .layer(from_fn_with_state(trusted, forwarded_peer));
+        let mut req = Request::builder().uri("/");
+        for header in forwarded_for {
+            req = req.header("x-forwarded-for", *header);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip(peer), 4000)));
+        let res = app.oneshot(req).await.unwrap();
+        String::from_utf8(to_bytes(res.into_body(), 1024).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwarded_hops_are_walked_from_the_right() {
+        // untrusted peers can't claim another address
+        assert_eq!(client_of("192.0.2.9", &["198.51.100.1"]).await, "192.0.2.9");
+        assert_eq!(client_of("10.0.0.1", &[]).await, "10.0.0.1");
+        assert_eq!(client_of("10.0.0.1", &["198.51.100.1"]).await, "198.51.100.1");
+        // the client can prepend anything; only hops our proxies added count
+        assert_eq!(client_of("10.0.0.1", &["203.0.113.5, 198.51.100.1, 10.0.0.2"]).await, "198.51.100.1");
+        assert_eq!(client_of("10.0.0.1", &["203.0.113.5", "198.51.100.1"]).await, "198.51.100.1");
+        // a garbled hop stops the walk at the last good one
+        assert_eq!(client_of("10.0.0.1", &["198.51.100.1, junk, 10.0.0.2"]).await, "10.0.0.2");
+    }
+}
@@ -0,0 +1,106 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+#[derive(Default)]
+struct Inner {
+    started: AtomicBool,
+    notify: Notify,
+}
+
+/// Set once when the server starts shutting down. Long-polls, event
+/// streams and sockets wait on it so their connections can drain.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    pub fn start(&self) {
+        self.inner.started.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.inner.started.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait(&self) {
+        let notified = self.inner.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.is_started() {
+            return;
+        }
+        notified.await;
+    }
+
+    /// `wait()` for APIs that need a `'static` future.
+    pub fn wait_owned(&self) -> impl Future<Output = ()> + Send + 'static {
+        let shutdown = self.clone();
+        async move { shutdown.wait().await }
+    }
+}
+
+/// Resolves on SIGTERM or ctrl-c.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("can't listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("can't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Background tasks that shutdown waits for, e.g. questions being answered.
+#[derive(Default)]
+pub struct TaskTracker {
+    tasks: StdMutex<JoinSet<()>>,
+}
+
+impl TaskTracker {
+    fn lock(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.lock();
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(task);
+    }
+
+    /// Waits up to `limit` for the running tasks and aborts the rest.
+    /// Returns how many finished and how many were aborted.
+    pub async fn drain(&self, limit: Duration) -> (usize, usize) {
+        let mut tasks = std::mem::take(&mut *self.lock());
+        while tasks.try_join_next().is_some() {}
+        let running = tasks.len();
+        let _ = tokio::time::timeout(limit, async {
+            while tasks.join_next().await.is_some() {}
+        }).await;
+        let aborted = tasks.len();
+        tasks.abort_all();
+        (running - aborted, aborted)
+    }
+}
@@ -0,0 +1,165 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use anyhow::{Context, Result};
+use rand::Rng;
+use crate::string_enum;
+use crate::gpt::{Answer, QuestionParams};
+
+string_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Category {
+        Animal => "animal",
+        Person => "person",
+        Place => "place",
+        Object => "object",
+    }
+}
+
+const CATEGORIES: [Category; 4] = [
+    Category::Animal, Category::Person, Category::Place, Category::Object
+];
+
+const ANIMALS: &[&str] = &[
+    "Elephant", "Giraffe", "Penguin", "Dolphin", "Kangaroo", "Owl", "Octopus",
+    "Tiger", "Honey bee", "Crocodile", "Horse", "Panda", "Shark", "Camel",
+];
+
+const PERSONS: &[&str] = &[
+    "Albert Einstein", "Cleopatra", "Leonardo da Vinci", "Marie Curie",
+    "Napoleon Bonaparte", "William Shakespeare", "Mahatma Gandhi",
+    "Wolfgang Amadeus Mozart", "Charlie Chaplin", "Isaac Newton",
+];
+
+const PLACES: &[&str] = &[
+    "New York", "Paris", "Mount Everest", "Sahara", "Amazon River", "Venice",
+    "Great Wall of China", "Antarctica", "Tokyo", "Grand Canyon", "Prague",
+];
+
+const OBJECTS: &[&str] = &[
+    "Bicycle", "Umbrella", "Piano", "Toothbrush", "Telescope", "Candle",
+    "Smartphone", "Hammer", "Guitar", "Refrigerator", "Compass", "Kite",
+];
+
+impl Category {
+    fn builtin_subjects(&self) -> &'static [&'static str] {
+        match self {
+            Category::Animal => ANIMALS,
+            Category::Person => PERSONS,
+            Category::Place => PLACES,
+            Category::Object => OBJECTS,
+        }
+    }
+
+    pub fn random() -> Category {
+        CATEGORIES[rand::rng().random_range(0..CATEGORIES.len())]
+    }
+}
+
+/// Where new games take their secret subject from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SubjectSource {
+    #[default]
+    BuiltIn,
+    /// One subject per line, optionally prefixed by "category:".
+    File(PathBuf),
+    Llm,
+}
+
+impl FromStr for SubjectSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "builtin" => Ok(SubjectSource::BuiltIn),
+            "llm" => Ok(SubjectSource::Llm),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(SubjectSource::File(PathBuf::from(path))),
+                _ => anyhow::bail!("unknown subject source '{}' (builtin, llm or file:<path>)", s),
+            },
+        }
+    }
+}
+
+pub struct SubjectPicker {
+    source: SubjectSource,
+    file_subjects: Vec<(Option<Category>, String)>,
+}
+
+impl SubjectPicker {
+    pub fn new(source: &SubjectSource) -> Result<Self> {
+        let file_subjects = match source {
+            SubjectSource::File(path) => Self::load_file(path)?,
+            _ => Vec::new(),
+        };
+        Ok(Self { source: source.clone(), file_subjects })
+    }
+
+    fn load_file(path: &PathBuf) -> Result<Vec<(Option<Category>, String)>> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading subjects file at {}", path.display()))?;
+
+        let mut res = Vec::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = match line.split_once(':') {
+                Some((category, subject)) => match Category::from_str(category.trim()) {
+                    Ok(category) => (Some(category), subject.trim().to_owned()),
+                    Err(_) => (None, line.to_owned()),
+                },
+                None => (None, line.to_owned()),
+            };
+            res.push(entry);
+        }
+
+        if res.is_empty() {
+            anyhow::bail!("subjects file {} has no subjects", path.display());
+        }
+        Ok(res)
+    }
+
+    pub fn source(&self) -> &SubjectSource {
+        &self.source
+    }
+
+    /// Picks a subject without asking the LLM. Used directly for the
+    /// built-in and file sources and as a fallback for the LLM source.
+    pub fn pick_offline(&self, category: Option<Category>) -> String {
+        let mut rng = rand::rng();
+        if !self.file_subjects.is_empty() {
+            let matching: Vec<&String> = self.file_subjects
+                .iter()
+                .filter(|(c, _)| category.is_none() || *c == category)
+                .map(|(_, s)| s)
+                .collect();
+            if !matching.is_empty() {
+                return matching[rng.random_range(0..matching.len())].clone();
+            }
+        }
+        let subjects = category.unwrap_or_else(Category::random).builtin_subjects();
+        subjects[rng.random_range(0..subjects.len())].to_owned()
+    }
+
+    pub fn llm_request(category: Category) -> (String, QuestionParams) {
+        let mut params = QuestionParams::default();
+        params.set_instructions("Reply with the name only, no punctuation or comments.");
+
+        let question = format!(
+            "Pick a random well-known {}. Avoid the most obvious choices.", category);
+        (question, params)
+    }
+
+    pub fn parse_llm_subject(answer: &Answer) -> Result<String> {
+        let subject = answer.to_string().context("empty answer")?;
+        let subject = subject.trim().trim_end_matches('.').trim();
+        if subject.is_empty() || subject.len() > 60 {
+            anyhow::bail!("unusable subject from LLM: '{}'", subject);
+        }
+        Ok(subject.to_owned())
+    }
+}
@@ -0,0 +1,41 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A file in the temp dir, unique to the test process and removed on drop.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Starts out missing, even if an earlier run left it behind.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("gggame-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        Self(path)
+    }
+
+    pub fn with_contents(name: &str, contents: &str) -> Self {
+        let path = Self::new(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
@@ -14,8 +14,8 @@ pub enum TokenType {
 impl TokenType {
     fn leading_byte(&self) -> u8 {
         match self {
-            TokenType::Answer => 'a' as u8,
-            TokenType::Game => 'g' as u8,
+            TokenType::Answer => b'a',
+            TokenType::Game => b'g',
         }
     }
     fn get_token_type(token: &Token) -> Option<TokenType> {
@@ -67,11 +67,13 @@ impl Token {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        String::from_utf8_lossy(&self.token).to_string()
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.token).unwrap()
     }
+}
 
-    pub fn to_str(&self) -> &str {
-        std::str::from_utf8(&self.token).unwrap()
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.token))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,135 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs;
+use std::ops::AddAssign;
+use std::path::Path;
+use std::sync::Mutex as StdMutex;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Usage {
+    pub calls: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// Already included in `output_tokens`; reported separately for insight.
+    pub reasoning_tokens: u64,
+    pub cost_usd: f64,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens + self.output_tokens
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.calls += other.calls;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+        self.cost_usd += other.cost_usd;
+    }
+}
+
+/// USD per million tokens.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        let prices = [
+            ("gpt-5", ModelPrice { input: 1.25, output: 10.0 }),
+            ("gpt-5-mini", ModelPrice { input: 0.25, output: 2.0 }),
+            ("gpt-5-nano", ModelPrice { input: 0.05, output: 0.4 }),
+        ];
+        Self {
+            prices: prices.into_iter().map(|(m, p)| (m.to_owned(), p)).collect(),
+        }
+    }
+}
+
+impl PriceTable {
+    /// Loads `{"gpt-5": {"input": 1.25, "output": 10.0}, ...}`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading price table at {}", path.display()))?;
+        let prices = serde_json::from_str(&contents)
+            .with_context(|| format!("parsing price table at {}", path.display()))?;
+        Ok(Self { prices })
+    }
+
+    /// Responses report dated model names ("gpt-5-nano-2025-08-07"), so the
+    /// longest matching prefix wins.
+    fn price(&self, model: &str) -> Option<&ModelPrice> {
+        self.prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| price)
+    }
+
+    pub fn cost(&self, model: &str, usage: &Usage) -> f64 {
+        match self.price(model) {
+            Some(p) => (usage.input_tokens as f64 * p.input
+                + usage.output_tokens as f64 * p.output) / 1_000_000.0,
+            None => 0.0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    total: Usage,
+    per_model: HashMap<String, Usage>,
+}
+
+/// Process wide usage, broken down by model.
+pub struct UsageTracker {
+    prices: PriceTable,
+    totals: StdMutex<Totals>,
+}
+
+impl UsageTracker {
+    pub fn new(prices: PriceTable) -> Self {
+        Self {
+            prices,
+            totals: StdMutex::new(Totals::default()),
+        }
+    }
+
+    /// Prices one call, adds it to the totals and returns the priced usage.
+    pub fn record(&self, model: &str, mut usage: Usage) -> Usage {
+        usage.calls = 1;
+        usage.cost_usd = self.prices.cost(model, &usage);
+
+        let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        totals.total += usage;
+        *totals.per_model.entry(model.to_owned()).or_default() += usage;
+        usage
+    }
+
+    pub fn total(&self) -> Usage {
+        self.totals.lock().unwrap_or_else(|e| e.into_inner()).total
+    }
+
+    pub fn to_json(&self) -> Value {
+        let totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        json!({
+            "status": "ok",
+            "total": totals.total,
+            "per_model": totals.per_model,
+        })
+    }
+}
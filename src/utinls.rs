@@ -15,7 +15,14 @@ pub mod token_generator {
         iter::repeat_with(one_char).take(len).collect()
     }
 
+    pub const TOKEN_LEN: usize = 20;
+
     pub fn generate_token() -> String {
-        generate_random_string(20)
+        generate_random_string(TOKEN_LEN)
+    }
+
+    /// Whether `token` could have come from `generate_token`.
+    pub fn is_token(token: &str) -> bool {
+        token.len() == TOKEN_LEN && token.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
     }
 }
\ No newline at end of file